@@ -0,0 +1,7 @@
+fn main() {
+    // Exposes the triple the tool is compiled for as the default compilation target
+    println!(
+        "cargo:rustc-env=HOST_TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
+}
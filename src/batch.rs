@@ -0,0 +1,299 @@
+use crate::{
+    build::{self, ArtifactKind, BuildOptions, BuildRecord},
+    compile::CompilerBackend,
+    generate::GenerateMode,
+    grammar::{self, Grammar},
+    jobs,
+    queries::QuerySeverity,
+    source::{self, GitSource},
+};
+use anyhow::{anyhow, bail, Context};
+use log::{error, info};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+/// List of grammars built in one run, read from TOML or JSON
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrammarManifest {
+    pub grammars: Vec<GrammarEntry>,
+}
+
+/// One repository of a manifest, options left out fall back to the command line
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GrammarEntry {
+    /// Grammar to build, every grammar the repository declares is built when left out
+    pub name: Option<String>,
+    /// Existing repository checkout, relative to the manifest
+    pub location: Option<PathBuf>,
+    /// Repository to check out instead of using `location`
+    pub git: Option<String>,
+    /// Commit or tag checked out from `git`, or that `location` is expected to be at
+    pub revision: Option<String>,
+    /// Directory of the grammar within the repository
+    #[serde(alias = "subpath")]
+    pub subdirectory: Option<PathBuf>,
+    /// Query files published instead of the repository's, relative to the manifest
+    pub queries: Option<PathBuf>,
+    pub targets: Option<Vec<String>>,
+    pub kinds: Option<Vec<ArtifactKind>>,
+    pub compiler: Option<CompilerBackend>,
+    pub generate: Option<GenerateMode>,
+    /// ABI version passed to `tree-sitter generate --abi`
+    pub abi: Option<u32>,
+    /// How query files that do not match the parser are treated
+    pub invalid_queries: Option<QuerySeverity>,
+    /// Whether to run the grammar's corpus tests
+    pub test_corpus: Option<bool>,
+}
+
+impl GrammarManifest {
+    /// Reads the manifest at `path`, parsed as JSON for `.json` files and TOML otherwise
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut manifest: GrammarManifest = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+
+        let base = path.parent().unwrap_or(Path::new("."));
+        let mut names = HashSet::new();
+        for grammar in &mut manifest.grammars {
+            // Grammars are built into a directory of their name
+            if let Some(name) = &grammar.name {
+                if !names.insert(name.clone()) {
+                    bail!("Grammar {} is listed more than once", name);
+                }
+            }
+            if let Some(queries) = &mut grammar.queries {
+                *queries = base.join(&*queries);
+            }
+            match (&mut grammar.location, &mut grammar.git) {
+                (Some(location), None) => *location = base.join(&*location),
+                (None, Some(url)) => {
+                    // Local repositories are given relative to the manifest as well
+                    if base.join(&*url).exists() {
+                        *url = base.join(&*url).to_string_lossy().to_string();
+                    }
+                }
+                _ => bail!(
+                    "Grammar {} needs exactly one of \"location\" and \"git\"",
+                    grammar.label()
+                ),
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+impl GrammarEntry {
+    /// Grammar name, or the repository name for entries building every grammar
+    pub fn label(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        let repository = match (&self.location, &self.git) {
+            (Some(location), _) => location.to_string_lossy().to_string(),
+            (None, Some(url)) => url.clone(),
+            (None, None) => String::new(),
+        };
+        let repository = repository.trim_end_matches('/');
+        let repository = repository.rsplit(['/', ':']).next().unwrap_or(repository);
+        repository
+            .strip_suffix(".git")
+            .unwrap_or(repository)
+            .to_string()
+    }
+
+    /// Command line options with the overrides of this entry applied
+    pub fn options(&self, defaults: &BuildOptions) -> BuildOptions {
+        let mut options = defaults.clone();
+        if let Some(targets) = &self.targets {
+            options.targets = targets.clone();
+        }
+        if let Some(kinds) = &self.kinds {
+            options.kinds = kinds.clone();
+        }
+        if let Some(compiler) = self.compiler {
+            options.compiler = compiler;
+        }
+        if let Some(generate) = self.generate {
+            options.generate.mode = generate;
+        }
+        if let Some(abi) = self.abi {
+            options.generate.abi = Some(abi);
+        }
+        if let Some(invalid_queries) = self.invalid_queries {
+            options.invalid_queries = invalid_queries;
+        }
+        if let Some(test_corpus) = self.test_corpus {
+            options.test_corpus = test_corpus;
+        }
+        options
+    }
+}
+
+/// Checks that the checkout at `location` is at the pinned `revision`
+fn check_revision(location: &Path, revision: &str) -> anyhow::Result<()> {
+    let expected = source::rev_parse(location, revision)?;
+    let actual = source::rev_parse(location, "HEAD")?;
+    if expected != actual {
+        bail!(
+            "{} is checked out at {}, but the manifest pins {}",
+            location.display(),
+            actual,
+            revision
+        );
+    }
+    Ok(())
+}
+
+/// Checks out the repository of the `index`th entry `grammar`, returning the grammars to build
+/// from it
+fn checkout(
+    index: usize,
+    grammar: &GrammarEntry,
+    artifact_path: &Path,
+) -> anyhow::Result<(Vec<Grammar>, Option<GitSource>)> {
+    let (root, git) = match (&grammar.location, &grammar.git) {
+        (_, Some(url)) => {
+            // Entries may share a repository, e.g. for its subdirectories or other revisions
+            let checkout = artifact_path
+                .join("checkouts")
+                .join(index.to_string())
+                .join(grammar.label());
+            let git = source::fetch(url, grammar.revision.as_deref(), &checkout)?;
+            (checkout, Some(git))
+        }
+        (Some(location), None) => {
+            if let Some(revision) = &grammar.revision {
+                check_revision(location, revision)?;
+            }
+            (location.clone(), None)
+        }
+        (None, None) => bail!("No location given for {}", grammar.label()),
+    };
+    if !root.is_dir() {
+        bail!("Grammar directory {} does not exist", root.display());
+    }
+
+    let mut grammars = match &grammar.name {
+        Some(name) => vec![grammar::select(
+            &root,
+            grammar.subdirectory.as_deref(),
+            Some(name),
+        )?],
+        None => {
+            let grammars = grammar::discover_in(&root, grammar.subdirectory.as_deref())?;
+            if grammars.is_empty() {
+                bail!("No grammar found in {}", root.display());
+            }
+            grammars
+        }
+    };
+    for selected in &mut grammars {
+        selected.queries_dir = grammar.queries.clone();
+    }
+    Ok((grammars, git))
+}
+
+/// Builds the grammars of the `index`th manifest entry, one after another
+///
+/// Grammars whose name another entry already claimed in `built` fail instead of overwriting its
+/// artifacts.
+fn build_entry(
+    index: usize,
+    entry: &GrammarEntry,
+    defaults: &BuildOptions,
+    artifact_path: &Path,
+    built: &Mutex<HashSet<String>>,
+) -> Vec<(String, anyhow::Result<BuildRecord>)> {
+    let (grammars, git) = match checkout(index, entry, artifact_path) {
+        Ok(checkout) => checkout,
+        Err(err) => {
+            error!("Failed to check out {}: {:#}", entry.label(), err);
+            return vec![(entry.label(), Err(err))];
+        }
+    };
+    let options = entry.options(defaults);
+    grammars
+        .into_iter()
+        .map(|grammar| {
+            info!("Building {}", grammar.name);
+            let result = if !built.lock().unwrap().insert(grammar.name.clone()) {
+                Err(anyhow!(
+                    "Grammar {} is built by another entry of the manifest",
+                    grammar.name
+                ))
+            } else if grammar.path.is_dir() {
+                build::build(
+                    &grammar,
+                    git.as_ref(),
+                    &options,
+                    &artifact_path.join(&grammar.name),
+                )
+            } else {
+                Err(anyhow!(
+                    "Grammar directory {} does not exist",
+                    grammar.path.display()
+                ))
+            };
+            match &result {
+                Ok(_) => info!("Finished {}", grammar.name),
+                Err(err) => error!("Failed to build {}: {:#}", grammar.name, err),
+            }
+            (grammar.name, result)
+        })
+        .collect()
+}
+
+/// Builds every grammar of `manifest` into its own directory below `artifact_path`
+///
+/// Up to `--jobs` entries are built at once. A failing grammar does not stop the run, its error
+/// is returned next to its name instead.
+pub fn build_all(
+    manifest: &GrammarManifest,
+    defaults: &BuildOptions,
+    artifact_path: &Path,
+) -> Vec<(String, anyhow::Result<BuildRecord>)> {
+    let next = AtomicUsize::new(0);
+    let built = Mutex::new(HashSet::new());
+    let worker = || {
+        let mut results = Vec::new();
+        loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let Some(entry) = manifest.grammars.get(index) else {
+                break;
+            };
+            results.extend(
+                build_entry(index, entry, defaults, artifact_path, &built)
+                    .into_iter()
+                    .map(|result| (index, result)),
+            );
+        }
+        results
+    };
+    let mut results: Vec<_> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..defaults.jobs().min(manifest.grammars.len()))
+            .map(|_| scope.spawn(worker))
+            .collect();
+        workers.into_iter().flat_map(jobs::join).collect()
+    });
+    // Report in manifest order regardless of which worker finished first
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
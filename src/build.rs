@@ -0,0 +1,492 @@
+use crate::{
+    cache::{self, Cache, Key},
+    compile::{self, Compiler, CompilerBackend},
+    corpus::{self, CorpusRecord},
+    generate::{self, GenerateMode, GenerateOptions},
+    grammar::{Grammar, GrammarMetadata},
+    jobs, library,
+    queries::{self, QuerySeverity},
+    source::GitSource,
+    target,
+};
+use anyhow::{bail, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+};
+
+const BUILD_RECORD: &str = "build.json";
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArtifactKind {
+    /// Shared library loadable by the tree-sitter C runtime
+    CDynlib,
+    /// Static archive for linking grammars into an application
+    CStatic,
+    /// WebAssembly module for web-tree-sitter and the tree-sitter wasm store
+    Wasm,
+}
+
+impl ArtifactKind {
+    /// File name of the artifact for `grammar_name` when built for `target`
+    ///
+    /// Shared libraries follow the tree-sitter loader convention of omitting the `lib` prefix.
+    pub fn file_name(self, grammar_name: &str, target: &str) -> String {
+        match self {
+            ArtifactKind::CDynlib if target::is_windows(target) => format!("{}.dll", grammar_name),
+            ArtifactKind::CDynlib if target::is_apple(target) => format!("{}.dylib", grammar_name),
+            ArtifactKind::CDynlib => format!("{}.so", grammar_name),
+            ArtifactKind::CStatic if target::is_msvc(target) => format!("{}.lib", grammar_name),
+            ArtifactKind::CStatic => format!("lib{}.a", grammar_name),
+            ArtifactKind::Wasm => format!("tree-sitter-{}.wasm", grammar_name),
+        }
+    }
+}
+
+impl std::fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = clap::ValueEnum::to_possible_value(self).expect("no skipped variants");
+        f.write_str(value.get_name())
+    }
+}
+
+#[derive(clap::Args)]
+pub struct BuildArgs {
+    /// Path to parser library root
+    #[clap(long, default_value = ".")]
+    pub grammar_path: PathBuf,
+    /// Git repository to check the grammar out from instead of `--grammar-path`
+    #[clap(long, conflicts_with = "grammar_path")]
+    pub git: Option<String>,
+    /// Commit, tag or branch of `--git`, defaults to the default branch
+    #[clap(long, requires = "git")]
+    pub revision: Option<String>,
+    /// Directory of the grammar within the repository, e.g. `tsx` in tree-sitter-typescript,
+    /// found through the repository's grammar declarations by default
+    #[clap(long)]
+    pub subpath: Option<PathBuf>,
+    /// Directory of query files to publish instead of the grammar's `queries`
+    #[clap(long)]
+    pub queries: Option<PathBuf>,
+    /// Grammar name, selecting one grammar of a repository that declares several, read from
+    /// `tree-sitter.json` or `package.json` by default
+    #[clap(short, long)]
+    pub grammar_name: Option<String>,
+    #[clap(flatten)]
+    pub options: BuildOptions,
+}
+
+/// Build settings shared by all grammars of a run
+#[derive(clap::Args, Clone)]
+pub struct BuildOptions {
+    /// Compilation targets, each published as one platform of the image index
+    #[clap(
+        short,
+        long = "target",
+        value_delimiter = ',',
+        default_value = target::HOST
+    )]
+    pub targets: Vec<String>,
+    /// Kinds of artifacts to build
+    #[clap(long = "kind", value_delimiter = ',', default_value = "c-dynlib")]
+    pub kinds: Vec<ArtifactKind>,
+    /// Compiler used for the native artifacts of every target
+    #[clap(long, default_value = "cc")]
+    pub compiler: CompilerBackend,
+    #[clap(flatten)]
+    pub generate: GenerateOptions,
+    /// Path to a wasi-sdk installation used for wasm builds, `clang` from PATH is used otherwise
+    #[clap(long, env = "WASI_SDK_PATH")]
+    pub wasi_sdk: Option<PathBuf>,
+
+    /// Directory of the local build cache, defaults to `$XDG_CACHE_HOME/ts-parser-registry`
+    #[clap(long, env = "TS_PARSER_REGISTRY_CACHE")]
+    pub cache_dir: Option<PathBuf>,
+    /// Always rebuild instead of reusing cached artifacts
+    #[clap(long)]
+    pub no_cache: bool,
+    /// Run the grammar's `test/corpus` against the library built for the host, failing the build
+    /// if a test fails
+    #[clap(long)]
+    pub test_corpus: bool,
+    /// How query files that do not match the built parser are treated
+    #[clap(long, default_value = "deny")]
+    pub invalid_queries: QuerySeverity,
+    /// Number of compilers and generators run at once, defaults to the number of CPUs
+    #[clap(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+}
+
+impl BuildOptions {
+    pub fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, usize::from)
+    }
+}
+
+/// Outputs of a finished build, consumed by `push`
+#[derive(Serialize, Deserialize)]
+pub struct BuildRecord {
+    pub name: String,
+    /// Version the grammar declares, used when pushing without `--grammar-version`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub metadata: GrammarMetadata,
+    pub sources: PathBuf,
+    /// ABI version of the parser, `LANGUAGE_VERSION` in `parser.c`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language_version: Option<u32>,
+    pub targets: Vec<TargetRecord>,
+    /// Target independent wasm module, published next to the library of every target
+    pub wasm: Option<PathBuf>,
+    /// Directory of the collected query files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queries: Option<PathBuf>,
+    /// Result of the corpus tests, if they were run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub corpus: Option<CorpusRecord>,
+    /// Repository the sources were checked out from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitSource>,
+}
+
+/// Artifacts compiled for a single target
+#[derive(Serialize, Deserialize)]
+pub struct TargetRecord {
+    pub target: String,
+    pub library: Option<PathBuf>,
+    pub static_library: Option<PathBuf>,
+}
+
+impl BuildRecord {
+    pub fn load(artifact_path: &Path) -> anyhow::Result<Self> {
+        let path = artifact_path.join(BUILD_RECORD);
+        let record = std::fs::read(&path).with_context(|| {
+            format!(
+                "Failed to read {}, run \"build\" before \"push\"",
+                path.display()
+            )
+        })?;
+        Ok(serde_json::from_slice(&record)?)
+    }
+
+    /// Fails if the corpus tests were run and did not pass
+    pub fn check_corpus(&self) -> anyhow::Result<()> {
+        if self.corpus.as_ref().is_some_and(|corpus| !corpus.passed) {
+            bail!("Corpus tests of {} failed", self.name);
+        }
+        Ok(())
+    }
+}
+
+/// Generates and compiles a grammar, recording the result in the artifact directory
+///
+/// Artifacts whose inputs match a cache entry are restored from the cache, and generation is
+/// skipped entirely when every requested artifact was cached. Targets are built in parallel,
+/// each logging as one block once it finishes.
+pub fn build(
+    grammar: &Grammar,
+    git: Option<&GitSource>,
+    args: &BuildOptions,
+    artifact_path: &Path,
+) -> anyhow::Result<BuildRecord> {
+    let grammar_path = grammar.path.as_path();
+    let grammar_name = grammar.name.as_str();
+    let src_dir = grammar_path.join("src");
+    std::fs::create_dir_all(artifact_path)?;
+    // Artifacts are overwritten before they are checked, a failed build must not leave the
+    // record of the previous one for `push` to publish them with
+    let record_path = artifact_path.join(BUILD_RECORD);
+    if record_path.exists() {
+        std::fs::remove_file(&record_path)?;
+    }
+
+    let cache = if args.no_cache {
+        None
+    } else {
+        Some(Cache::new(args.cache_dir.as_deref())?)
+    };
+    let mut sources_key = Key::default();
+    sources_key
+        .update("tool", env!("CARGO_PKG_VERSION"))
+        .update("grammar name", grammar_name)
+        .update("generate", args.generate.mode.to_string())
+        .update(
+            "tree-sitter version",
+            args.generate
+                .tree_sitter_version
+                .as_deref()
+                .unwrap_or_default(),
+        )
+        .update(
+            "abi",
+            args.generate
+                .abi
+                .map(|abi| abi.to_string())
+                .unwrap_or_default(),
+        )
+        .update("from json", args.generate.from_json.to_string());
+    // Parsers generated by another CLI may differ even when no version is pinned
+    if args.generate.mode != GenerateMode::Never {
+        sources_key.update(
+            "installed tree-sitter version",
+            generate::installed_cli_version().unwrap_or_default(),
+        );
+    }
+    cache::add_grammar_sources(&mut sources_key, grammar_path, &src_dir)?;
+
+    let prepared = Mutex::new(None);
+    let build_artifact = |kind: ArtifactKind,
+                          target: &str,
+                          compiler: &Compiler,
+                          dst_dir: &Path|
+     -> anyhow::Result<PathBuf> {
+        let file_name = kind.file_name(grammar_name, target);
+        let path = dst_dir.join(&file_name);
+        let obj_dir = artifact_path.join(target).join("obj");
+        let key = sources_key
+            .clone()
+            .update("kind", kind.to_string())
+            .update("target", target)
+            .update("compiler", compiler.identity()?)
+            .finish();
+        if let Some(cache) = &cache {
+            if cache.restore(&key, &path)? {
+                info!(
+                    "Reusing cached {} {} artifact for {}",
+                    grammar_name, kind, target
+                );
+                return Ok(path.canonicalize()?);
+            }
+        }
+
+        let src_dir = {
+            // Targets wait here for the one that generates the parser
+            let mut prepared = prepared.lock().unwrap();
+            if prepared.is_none() {
+                *prepared = Some(generate::prepare_sources(
+                    grammar_path,
+                    &args.generate,
+                    artifact_path,
+                )?);
+            }
+            prepared.clone().expect("sources were prepared above")
+        };
+        match kind {
+            ArtifactKind::CDynlib => compile::compile_c_dynlib(
+                compiler, &src_dir, &obj_dir, dst_dir, &file_name, target,
+            )?,
+            ArtifactKind::CStatic => compile::compile_c_static(
+                compiler, &src_dir, &obj_dir, dst_dir, &file_name, target,
+            )?,
+            ArtifactKind::Wasm => {
+                compile::compile_wasm(compiler, &src_dir, dst_dir, &file_name, grammar_name)?
+            }
+        }
+        info!(
+            "Finished compilation of {} {} artifact for {}",
+            grammar_name, kind, target
+        );
+        if let Some(cache) = &cache {
+            cache.store(&key, &path)?;
+        }
+        Ok(path.canonicalize()?)
+    };
+
+    let build_target = |target: &String| -> anyhow::Result<TargetRecord> {
+        let compiler = Compiler::new(args.compiler, target)?;
+        let target_path = artifact_path.join(target);
+        let library = args
+            .kinds
+            .contains(&ArtifactKind::CDynlib)
+            .then(|| {
+                build_artifact(
+                    ArtifactKind::CDynlib,
+                    target,
+                    &compiler,
+                    &target_path.join("c-dynlib"),
+                )
+            })
+            .transpose()?;
+        let static_library = args
+            .kinds
+            .contains(&ArtifactKind::CStatic)
+            .then(|| {
+                build_artifact(
+                    ArtifactKind::CStatic,
+                    target,
+                    &compiler,
+                    &target_path.join("c-static"),
+                )
+            })
+            .transpose()?;
+        Ok(TargetRecord {
+            target: target.clone(),
+            library,
+            static_library,
+        })
+    };
+    let build_wasm = || {
+        args.kinds
+            .contains(&ArtifactKind::Wasm)
+            .then(|| {
+                build_artifact(
+                    ArtifactKind::Wasm,
+                    target::WASM,
+                    &Compiler::wasm(args.wasi_sdk.as_deref()),
+                    &artifact_path.join("wasm"),
+                )
+            })
+            .transpose()
+    };
+
+    let (target_records, wasm) = thread::scope(|scope| {
+        let targets: Vec<_> = args
+            .targets
+            .iter()
+            .map(|target| scope.spawn(|| jobs::logged(|| build_target(target))))
+            .collect();
+        let wasm = scope.spawn(|| jobs::logged(build_wasm));
+        let target_records = targets
+            .into_iter()
+            .map(jobs::join)
+            .collect::<anyhow::Result<Vec<_>>>();
+        (target_records, jobs::join(wasm))
+    });
+    let target_records = target_records?;
+    let wasm = wasm?;
+
+    // The sources that were compiled, which may differ from the committed `src`
+    let sources_key = sources_key.update("kind", "sources").finish();
+    let sources = match prepared.into_inner().unwrap() {
+        Some(sources) => {
+            if let Some(cache) = &cache {
+                cache.store_dir(&sources_key, &sources)?;
+            }
+            sources
+        }
+        // Every artifact was cached, so are the sources they were compiled from
+        None => {
+            let restored = artifact_path.join(generate::GENERATED_DIR).join("src");
+            match &cache {
+                Some(cache) if cache.restore_dir(&sources_key, &restored)? => restored,
+                _ => generate::prepare_sources(grammar_path, &args.generate, artifact_path)?,
+            }
+        }
+    };
+    let mut language_version = generate::language_version(&sources)?;
+    for record in &target_records {
+        let Some(library) = &record.library else {
+            continue;
+        };
+        let Some(abi) = library::check(library, &record.target, grammar_name)? else {
+            continue;
+        };
+        match language_version {
+            Some(expected) if expected != abi => bail!(
+                "{} has ABI {}, but the parser defines LANGUAGE_VERSION {}",
+                library.display(),
+                abi,
+                expected
+            ),
+            _ => language_version = Some(abi),
+        }
+    }
+
+    let queries_dir = artifact_path.join("queries");
+    let queries = queries::collect(grammar, &queries_dir)?
+        .then(|| queries_dir.canonicalize())
+        .transpose()?;
+    if let Some(queries) = &queries {
+        queries::validate(queries, &sources, args.invalid_queries)
+            .with_context(|| format!("Invalid queries in grammar {}", grammar_name))?;
+    }
+
+    let corpus = if args.test_corpus {
+        let library = target_records
+            .iter()
+            .find(|record| record.target == target::HOST)
+            .and_then(|record| record.library.as_deref())
+            .with_context(|| {
+                format!(
+                    "Corpus tests need the c-dynlib artifact for the host target {}",
+                    target::HOST
+                )
+            })?;
+        corpus::test(&grammar.path, grammar_name, library)?
+    } else {
+        None
+    };
+
+    let record = BuildRecord {
+        name: grammar_name.to_string(),
+        version: grammar.version.clone(),
+        metadata: grammar.metadata.clone(),
+        sources: sources.canonicalize()?,
+        language_version,
+        targets: target_records,
+        wasm,
+        queries,
+        corpus,
+        git: git.cloned(),
+    };
+    std::fs::write(&record_path, serde_json::to_vec_pretty(&record)?)?;
+    record.check_corpus()?;
+
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names() {
+        let cases = [
+            (ArtifactKind::CDynlib, "x86_64-unknown-linux-gnu", "rust.so"),
+            (
+                ArtifactKind::CDynlib,
+                "x86_64-unknown-linux-musl",
+                "rust.so",
+            ),
+            (ArtifactKind::CDynlib, "aarch64-apple-darwin", "rust.dylib"),
+            (ArtifactKind::CDynlib, "x86_64-pc-windows-msvc", "rust.dll"),
+            (ArtifactKind::CDynlib, "x86_64-pc-windows-gnu", "rust.dll"),
+            (
+                ArtifactKind::CDynlib,
+                "armv7-unknown-linux-gnueabihf",
+                "rust.so",
+            ),
+            (
+                ArtifactKind::CStatic,
+                "x86_64-unknown-linux-gnu",
+                "librust.a",
+            ),
+            (ArtifactKind::CStatic, "aarch64-apple-darwin", "librust.a"),
+            (ArtifactKind::CStatic, "i686-pc-windows-msvc", "rust.lib"),
+            (ArtifactKind::CStatic, "x86_64-pc-windows-gnu", "librust.a"),
+            (ArtifactKind::Wasm, target::WASM, "tree-sitter-rust.wasm"),
+            (
+                ArtifactKind::Wasm,
+                "x86_64-pc-windows-msvc",
+                "tree-sitter-rust.wasm",
+            ),
+        ];
+        for (kind, target, file_name) in cases {
+            assert_eq!(
+                kind.file_name("rust", target),
+                file_name,
+                "{} {}",
+                kind,
+                target
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::Context;
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Distinguishes the partial files of concurrent stores within this process
+static PARTIAL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Local content-addressed store of compiled artifacts
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Opens the cache at `dir`, defaulting to `$XDG_CACHE_HOME/ts-parser-registry`
+    pub fn new(dir: Option<&Path>) -> anyhow::Result<Self> {
+        let dir = match dir {
+            Some(dir) => dir.to_path_buf(),
+            None => std::env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+                .context("Cannot determine cache directory, pass --cache-dir")?
+                .join("ts-parser-registry"),
+        };
+        Ok(Cache { dir })
+    }
+
+    /// Copies the artifact stored under `key` to `path`, returning whether it was found
+    pub fn restore(&self, key: &str, path: &Path) -> anyhow::Result<bool> {
+        let entry = self.dir.join(key);
+        if !entry.is_file() {
+            return Ok(false);
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&entry, path)
+            .with_context(|| format!("Failed to restore {} from cache", path.display()))?;
+        debug!("Restored {} from cache entry {}", path.display(), key);
+        Ok(true)
+    }
+
+    /// Path next to the entry of `key` to write to first, so that concurrent readers never see
+    /// a partial file
+    fn partial(&self, key: &str) -> anyhow::Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)?;
+        Ok(self.dir.join(format!(
+            "{}.{}-{}.partial",
+            key,
+            std::process::id(),
+            PARTIAL_ID.fetch_add(1, Ordering::Relaxed)
+        )))
+    }
+
+    /// Stores the artifact at `path` under `key`
+    pub fn store(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let partial = self.partial(key)?;
+        std::fs::copy(path, &partial)
+            .with_context(|| format!("Failed to store {} in cache", path.display()))?;
+        std::fs::rename(&partial, self.dir.join(key))?;
+        Ok(())
+    }
+
+    /// Replaces `dir` with the directory tree stored under `key`, returning whether it was found
+    pub fn restore_dir(&self, key: &str, dir: &Path) -> anyhow::Result<bool> {
+        let entry = self.dir.join(key);
+        if !entry.is_file() {
+            return Ok(false);
+        }
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+        std::fs::create_dir_all(dir)?;
+        let archive = std::fs::File::open(&entry)?;
+        tar::Archive::new(archive)
+            .unpack(dir)
+            .with_context(|| format!("Failed to restore {} from cache", dir.display()))?;
+        debug!("Restored {} from cache entry {}", dir.display(), key);
+        Ok(true)
+    }
+
+    /// Stores the directory tree at `dir` under `key`, as a tar archive
+    pub fn store_dir(&self, key: &str, dir: &Path) -> anyhow::Result<()> {
+        let partial = self.partial(key)?;
+        let mut archive = tar::Builder::new(std::fs::File::create(&partial)?);
+        archive.mode(tar::HeaderMode::Deterministic);
+        archive
+            .append_dir_all(".", dir)
+            .with_context(|| format!("Failed to store {} in cache", dir.display()))?;
+        archive.into_inner()?.sync_all()?;
+        std::fs::rename(&partial, self.dir.join(key))?;
+        Ok(())
+    }
+}
+
+/// Incrementally computed cache key
+#[derive(Clone, Default)]
+pub struct Key(Sha256);
+
+impl Key {
+    pub fn update(&mut self, label: &str, data: impl AsRef<[u8]>) -> &mut Self {
+        // Length prefixes keep adjacent components from running into each other
+        let data = data.as_ref();
+        self.0.update((label.len() as u64).to_le_bytes());
+        self.0.update(label);
+        self.0.update((data.len() as u64).to_le_bytes());
+        self.0.update(data);
+        self
+    }
+
+    pub fn update_file(&mut self, label: &str, path: &Path) -> anyhow::Result<&mut Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(self.update(label, data))
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{:x}", self.0.clone().finalize())
+    }
+}
+
+/// Relative `require()` paths of a grammar file, resolved against its directory
+fn requires(path: &Path, source: &str) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    source
+        .split("require(")
+        .skip(1)
+        .filter_map(|call| {
+            let quote = call.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+            let argument = call[1..].split(quote).next()?;
+            argument.starts_with('.').then(|| dir.join(argument))
+        })
+        .map(|include| {
+            if include.is_file() {
+                include
+            } else {
+                let mut include = include.into_os_string();
+                include.push(".js");
+                PathBuf::from(include)
+            }
+        })
+        .filter(|include| include.is_file())
+        .collect()
+}
+
+/// Adds `grammar.js` with everything it includes and the contents of `src_dir` to `key`
+pub fn add_grammar_sources(
+    key: &mut Key,
+    grammar_path: &Path,
+    src_dir: &Path,
+) -> anyhow::Result<()> {
+    let mut pending = vec![grammar_path.join("grammar.js")];
+    let mut visited = BTreeSet::new();
+    while let Some(path) = pending.pop() {
+        let path = path.canonicalize().unwrap_or(path);
+        if !path.is_file() || !visited.insert(path.clone()) {
+            continue;
+        }
+        let source = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        pending.extend(requires(&path, &source));
+    }
+    for path in &visited {
+        key.update_file("grammar", path)?;
+    }
+
+    let mut files = Vec::new();
+    // Grammars without committed sources only have `grammar.js`
+    let mut dirs: Vec<PathBuf> = src_dir
+        .is_dir()
+        .then(|| src_dir.to_path_buf())
+        .into_iter()
+        .collect();
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    for path in files {
+        let relative = path.strip_prefix(src_dir)?.to_string_lossy().to_string();
+        key.update_file(&relative, &path)?;
+    }
+    Ok(())
+}
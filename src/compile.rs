@@ -0,0 +1,348 @@
+use crate::{cache::Key, jobs, target};
+use anyhow::{bail, Context};
+use log::debug;
+use serde::Deserialize;
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompilerBackend {
+    /// C compiler located by the `cc` crate, honoring `CC_<target>`/`CXX_<target>` and `AR_<target>`
+    Cc,
+    /// `zig cc -target <triple>`, or `zig c++` for C++ scanners, cross compiling for every target
+    /// without a separate sysroot
+    Zig,
+}
+
+fn is_cpp(source: &Path) -> bool {
+    source.extension() == Some("cc".as_ref())
+}
+
+fn scanner_path(src_dir: &Path) -> Option<PathBuf> {
+    let c_scanner = src_dir.join("scanner.c");
+    let cpp_scanner = src_dir.join("scanner.cc");
+    if c_scanner.is_file() {
+        Some(c_scanner)
+    } else if cpp_scanner.is_file() {
+        Some(cpp_scanner)
+    } else {
+        None
+    }
+}
+
+/// C compiler invocation for one target
+pub struct Compiler {
+    program: PathBuf,
+    args: Vec<OsString>,
+    /// Arguments replacing `args` when compiling and linking C++, if the driver differs
+    cpp_args: Option<Vec<OsString>>,
+    env: Vec<(OsString, OsString)>,
+    archiver: Vec<OsString>,
+    is_like_msvc: bool,
+    identity: OnceLock<String>,
+}
+
+impl Compiler {
+    /// Looks up the C compiler for `target`, cross compiling from the host this tool runs on
+    pub fn new(backend: CompilerBackend, target: &str) -> anyhow::Result<Self> {
+        match backend {
+            CompilerBackend::Cc => {
+                let mut config = cc::Build::new();
+                config
+                    .cpp(true)
+                    .opt_level(2)
+                    .cargo_metadata(false)
+                    .target(target)
+                    .host(target::HOST);
+                let tool = config
+                    .try_get_compiler()
+                    .with_context(|| format!("Failed to find a C compiler for {}", target))?;
+                let archiver = config.try_get_archiver()?;
+                Ok(Compiler {
+                    program: tool.path().to_path_buf(),
+                    args: tool.args().to_vec(),
+                    cpp_args: None,
+                    env: tool.env().to_vec(),
+                    archiver: std::iter::once(archiver.get_program())
+                        .chain(archiver.get_args())
+                        .map(OsString::from)
+                        .collect(),
+                    is_like_msvc: tool.is_like_msvc(),
+                    identity: OnceLock::new(),
+                })
+            }
+            CompilerBackend::Zig => {
+                let triple = target::zig_triple(target)?;
+                let driver =
+                    |driver: &str| vec![driver.into(), "-target".into(), triple.clone().into()];
+                Ok(Compiler {
+                    program: PathBuf::from("zig"),
+                    args: driver("cc"),
+                    cpp_args: Some(driver("c++")),
+                    env: Vec::new(),
+                    archiver: vec!["zig".into(), "ar".into()],
+                    is_like_msvc: false,
+                    identity: OnceLock::new(),
+                })
+            }
+        }
+    }
+
+    /// Clang from a wasi-sdk installation, or from PATH, producing wasm modules
+    pub fn wasm(wasi_sdk: Option<&Path>) -> Self {
+        let program = match wasi_sdk {
+            Some(wasi_sdk) => wasi_sdk.join("bin").join("clang"),
+            None => PathBuf::from("clang"),
+        };
+        Compiler {
+            program,
+            args: vec![format!("--target={}", target::WASM).into()],
+            cpp_args: None,
+            env: Vec::new(),
+            archiver: Vec::new(),
+            is_like_msvc: false,
+            identity: OnceLock::new(),
+        }
+    }
+
+    /// Program, arguments and version banner of the compiler, identifying it in cache keys
+    pub fn identity(&self) -> anyhow::Result<&str> {
+        if let Some(identity) = self.identity.get() {
+            return Ok(identity);
+        }
+        let mut command = self.command(false);
+        // MSVC prints its version banner when invoked without arguments
+        if !self.is_like_msvc {
+            command.arg("--version");
+        }
+        let output = {
+            let _slot = jobs::acquire();
+            command
+                .output()
+                .with_context(|| format!("Failed to execute {}", self.program.display()))?
+        };
+        let identity = format!(
+            "{} {:?}\n{}{}",
+            self.program.display(),
+            self.args,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Ok(self.identity.get_or_init(|| identity))
+    }
+
+    /// Compiler invocation for C, or for C++ when `cpp` is set
+    fn command(&self, cpp: bool) -> Command {
+        let mut command = Command::new(&self.program);
+        match &self.cpp_args {
+            Some(cpp_args) if cpp => command.args(cpp_args),
+            _ => command.args(&self.args),
+        };
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        command
+    }
+
+    fn archiver(&self) -> Command {
+        let mut command = Command::new(&self.archiver[0]);
+        command.args(&self.archiver[1..]);
+        command
+    }
+}
+
+fn run_compiler(command: Command) -> anyhow::Result<()> {
+    run_tool(command, "C compiler")
+}
+
+fn run_tool(mut command: Command, tool: &str) -> anyhow::Result<()> {
+    let _slot = jobs::acquire();
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to execute {}", tool))?;
+    if !output.status.success() {
+        bail!(
+            "Parser compilation failed.\nStdout: {}\nStderr: {}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Hash over everything an object file of `source` depends on besides the compiler flags
+fn object_key(compiler: &Compiler, src_dir: &Path, source: &Path) -> anyhow::Result<String> {
+    let mut key = Key::default();
+    key.update("compiler", compiler.identity()?)
+        .update_file("source", source)?;
+    let mut headers = Vec::new();
+    let mut dirs = vec![src_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension() == Some("h".as_ref()) {
+                headers.push(path);
+            }
+        }
+    }
+    headers.sort();
+    for header in headers {
+        let relative = header.strip_prefix(src_dir)?.to_string_lossy().to_string();
+        key.update_file(&relative, &header)?;
+    }
+    Ok(key.finish())
+}
+
+/// Compiles `parser.c` and the scanner to separate object files in `obj_dir`
+///
+/// Each object is stored next to the key of its inputs and only recompiled when that key
+/// changes, so that editing the scanner does not recompile the much larger parser.
+fn compile_objects(
+    compiler: &Compiler,
+    src_dir: &Path,
+    obj_dir: &Path,
+    target: &str,
+) -> anyhow::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(obj_dir)?;
+    let mut sources = vec![src_dir.join("parser.c")];
+    sources.extend(scanner_path(src_dir));
+
+    let mut objects = Vec::new();
+    for source in sources {
+        let object = obj_dir
+            .join(source.file_name().unwrap_or_default())
+            .with_extension(if compiler.is_like_msvc { "obj" } else { "o" });
+        let key_path = object.with_extension("key");
+        let key = object_key(compiler, src_dir, &source)?;
+        if object.is_file() && std::fs::read_to_string(&key_path).ok().as_ref() == Some(&key) {
+            debug!("Reusing {}", object.display());
+            objects.push(object);
+            continue;
+        }
+
+        let mut command = compiler.command(is_cpp(&source));
+        if compiler.is_like_msvc {
+            command
+                .args(["/nologo", "/c", "/O2", "/I"])
+                .arg(src_dir)
+                .arg(format!("/Fo{}", object.display()))
+                .arg(&source);
+        } else {
+            command.arg("-c");
+            // Position independent code is implied on Windows and rejected by some MinGW toolchains
+            if !target::is_windows(target) {
+                command.arg("-fPIC");
+            }
+            command
+                .arg("-fno-exceptions")
+                .arg("-g")
+                .arg("-O2")
+                .arg("-I")
+                .arg(src_dir)
+                .arg("-o")
+                .arg(&object);
+            if source.extension() == Some("c".as_ref()) {
+                command.arg("-xc").arg("-std=c99");
+            }
+            command.arg(&source);
+        }
+        run_compiler(command)?;
+        std::fs::write(&key_path, key)?;
+        objects.push(object);
+    }
+    Ok(objects)
+}
+
+pub fn compile_c_dynlib(
+    compiler: &Compiler,
+    src_dir: &Path,
+    obj_dir: &Path,
+    dst_dir: &Path,
+    dst_name: &str,
+    target: &str,
+) -> anyhow::Result<()> {
+    let objects = compile_objects(compiler, src_dir, obj_dir, target)?;
+    std::fs::create_dir_all(dst_dir)?;
+    let library_path = dst_dir.join(dst_name);
+
+    // A C++ scanner needs the C++ runtime linked in
+    let mut command = compiler.command(scanner_path(src_dir).is_some_and(|path| is_cpp(&path)));
+    if compiler.is_like_msvc {
+        command
+            .args(["/nologo", "/LD"])
+            .args(&objects)
+            .arg("/link")
+            .arg(format!("/out:{}", library_path.display()));
+    } else {
+        if target::is_apple(target) {
+            command.arg("-dynamiclib");
+        } else {
+            command.arg("-shared");
+        }
+        command.arg("-o").arg(&library_path).args(&objects);
+    }
+    run_compiler(command)
+}
+
+pub fn compile_c_static(
+    compiler: &Compiler,
+    src_dir: &Path,
+    obj_dir: &Path,
+    dst_dir: &Path,
+    dst_name: &str,
+    target: &str,
+) -> anyhow::Result<()> {
+    let objects = compile_objects(compiler, src_dir, obj_dir, target)?;
+    std::fs::create_dir_all(dst_dir)?;
+    let library_path = dst_dir.join(dst_name);
+
+    // Archivers add to existing archives instead of replacing them
+    if library_path.exists() {
+        std::fs::remove_file(&library_path)?;
+    }
+    let mut archiver = compiler.archiver();
+    if compiler.is_like_msvc {
+        archiver
+            .arg("/nologo")
+            .arg(format!("/OUT:{}", library_path.display()));
+    } else {
+        archiver.arg("crs").arg(&library_path);
+    }
+    archiver.args(&objects);
+    run_tool(archiver, "archiver")
+}
+
+pub fn compile_wasm(
+    compiler: &Compiler,
+    src_dir: &Path,
+    dst_dir: &Path,
+    dst_name: &str,
+    grammar_name: &str,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dst_dir)?;
+    let mut command = compiler.command(false);
+    command
+        .arg("-o")
+        .arg(dst_dir.join(dst_name))
+        .arg("-fPIC")
+        .arg("-shared")
+        .arg("-Os")
+        .arg(format!("-Wl,--export=tree_sitter_{}", grammar_name))
+        .arg("-Wl,--allow-undefined")
+        .arg("-Wl,--no-entry")
+        .arg("-nostdlib")
+        .arg("-fno-exceptions")
+        .arg("-fvisibility=hidden")
+        .arg("-I")
+        .arg(src_dir)
+        .arg(src_dir.join("parser.c"));
+    if let Some(scanner_path) = scanner_path(src_dir) {
+        command.arg(scanner_path);
+    }
+    run_compiler(command)
+}
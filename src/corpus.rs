@@ -0,0 +1,60 @@
+use crate::jobs;
+use anyhow::Context;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, process::Command};
+
+/// Outcome of running the corpus tests of a grammar against its built library
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CorpusRecord {
+    pub passed: bool,
+    /// Output of `tree-sitter test`
+    pub output: String,
+}
+
+/// Runs the `test/corpus` of the grammar at `grammar_path` against `library`
+///
+/// Returns `None` when the grammar has no corpus. The library is loaded by `tree-sitter test
+/// --lib-path`, so that the tested parser is the artifact being published rather than one the
+/// CLI compiles itself.
+pub fn test(
+    grammar_path: &Path,
+    language: &str,
+    library: &Path,
+) -> anyhow::Result<Option<CorpusRecord>> {
+    let corpus_dir = grammar_path.join("test").join("corpus");
+    if !corpus_dir.is_dir() {
+        warn!(
+            "{} does not exist, skipping corpus tests",
+            corpus_dir.display()
+        );
+        return Ok(None);
+    }
+    let library = library
+        .canonicalize()
+        .with_context(|| format!("Failed to find {}", library.display()))?;
+
+    let _slot = jobs::acquire();
+    info!("Running corpus tests of {}", language);
+    let output = Command::new("tree-sitter")
+        .arg("test")
+        .arg("--lib-path")
+        .arg(&library)
+        .arg("--lang-name")
+        .arg(language)
+        .current_dir(grammar_path)
+        .output()
+        .context("Failed to execute the tree-sitter CLI")?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    let record = CorpusRecord {
+        passed: output.status.success(),
+        output: text.trim().to_string(),
+    };
+    if record.passed {
+        info!("Corpus tests of {} passed", language);
+    } else {
+        warn!("Corpus tests of {} failed:\n{}", language, record.output);
+    }
+    Ok(Some(record))
+}
@@ -0,0 +1,301 @@
+use crate::jobs;
+use anyhow::{bail, Context};
+use log::{info, warn};
+use serde::Deserialize;
+use std::{
+    io::BufRead,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Directory of the artifact tree that generation runs in
+pub const GENERATED_DIR: &str = "generated";
+
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum GenerateMode {
+    /// Compile the committed `src` as is, without requiring the tree-sitter CLI
+    Never,
+    /// Always regenerate the parser from the grammar
+    Always,
+    /// Regenerate when `src/parser.c` is missing or `src/grammar.json` is out of date
+    ///
+    /// Checking for changes requires Node.js and the tree-sitter CLI, without them committed
+    /// sources are used unchecked.
+    IfStale,
+}
+
+impl std::fmt::Display for GenerateMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = clap::ValueEnum::to_possible_value(self).expect("no skipped variants");
+        f.write_str(value.get_name())
+    }
+}
+
+/// How and with which tree-sitter CLI parsers are generated
+#[derive(clap::Args, Clone)]
+pub struct GenerateOptions {
+    /// When to regenerate the parser instead of compiling the committed `src/parser.c`
+    #[clap(long = "generate", default_value = "if-stale")]
+    pub mode: GenerateMode,
+    /// Required version of the tree-sitter CLI, e.g. `0.22` or `0.22.6`
+    #[clap(long, env = "TS_CLI_VERSION")]
+    pub tree_sitter_version: Option<String>,
+    /// ABI version of generated parsers, passed to `tree-sitter generate --abi`
+    ///
+    /// With `--generate if-stale`, committed parsers of a different ABI are regenerated, with
+    /// `--generate never` they are rejected.
+    #[clap(long)]
+    pub abi: Option<u32>,
+    /// Generate from `src/grammar.json` instead of `grammar.js`, which does not need Node.js
+    ///
+    /// Used automatically when Node.js is not installed.
+    #[clap(long)]
+    pub from_json: bool,
+}
+
+fn is_installed(program: &str) -> bool {
+    Command::new(program)
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Output of `tree-sitter --version` for the CLI on PATH, `None` when it is not installed
+pub fn installed_cli_version() -> Option<String> {
+    let output = Command::new("tree-sitter").arg("--version").output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Copies the files of `src` that generation does not produce, like the scanner and its headers
+fn copy_grammar_sources(src: &Path, generated: &Path) -> anyhow::Result<()> {
+    if !src.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(src)? {
+        let path = entry?.path();
+        let destination = generated.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            std::fs::create_dir_all(&destination)?;
+            copy_grammar_sources(&path, &destination)?;
+        } else if !destination.exists() {
+            std::fs::copy(&path, &destination)
+                .with_context(|| format!("Failed to copy {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Grammar definition to generate from
+///
+/// `src/grammar.json` is used when requested, when there is no `grammar.js`, or when Node.js is
+/// missing to evaluate it.
+fn grammar_file(grammar_path: &Path, from_json: bool) -> anyhow::Result<PathBuf> {
+    let grammar_js = grammar_path.join("grammar.js");
+    let grammar_json = grammar_path.join("src").join("grammar.json");
+    let use_json =
+        from_json || (grammar_json.is_file() && (!grammar_js.is_file() || !is_installed("node")));
+    if !use_json {
+        return grammar_js
+            .canonicalize()
+            .with_context(|| format!("Failed to find {}", grammar_js.display()));
+    }
+    if !grammar_json.is_file() {
+        bail!(
+            "Cannot generate from {}, it does not exist",
+            grammar_json.display()
+        );
+    }
+    info!("Generating from {}", grammar_json.display());
+    Ok(grammar_json.canonicalize()?)
+}
+
+/// Checks that the tree-sitter CLI on PATH has the `required` version or a patch release of it
+fn check_cli_version(required: &str) -> anyhow::Result<()> {
+    let output = Command::new("tree-sitter")
+        .arg("--version")
+        .output()
+        .context("Failed to execute the tree-sitter CLI")?;
+    // Prints e.g. `tree-sitter 0.22.6 (b40f342067a89fd6331ce2b4b1ffdac1b4e9b2cb)`
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let Some(version) = stdout.split_whitespace().nth(1) else {
+        bail!("Unexpected output of \"tree-sitter --version\": {}", stdout);
+    };
+    let matches = version
+        .strip_prefix(required)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'));
+    if !matches {
+        bail!(
+            "tree-sitter CLI {} is installed, but version {} is required",
+            version,
+            required
+        );
+    }
+    Ok(())
+}
+
+/// Runs `tree-sitter generate` in a fresh `generated` directory of the artifact tree
+///
+/// Returns the generated `src` directory, completed with the scanner and headers of the grammar
+/// so that it can be compiled on its own.
+fn generate(
+    grammar_path: &Path,
+    options: &GenerateOptions,
+    artifact_path: &Path,
+) -> anyhow::Result<PathBuf> {
+    if let Some(required) = &options.tree_sitter_version {
+        check_cli_version(required)?;
+    }
+    let out_dir = artifact_path.join(GENERATED_DIR);
+    if out_dir.exists() {
+        std::fs::remove_dir_all(&out_dir)?;
+    }
+    std::fs::create_dir_all(&out_dir)?;
+
+    let _slot = jobs::acquire();
+    // The CLI runs inside the output directory, where a relative grammar path would not resolve
+    let grammar_file = grammar_file(grammar_path, options.from_json)?;
+    let mut command = Command::new("tree-sitter");
+    command.arg("generate");
+    if let Some(abi) = options.abi {
+        command.arg("--abi").arg(abi.to_string());
+    }
+    let generate_output = command
+        .arg(&grammar_file)
+        .current_dir(&out_dir)
+        .output()
+        .context("Failed to execute the tree-sitter CLI")?;
+    if !generate_output.status.success() {
+        bail!(
+            "Failed to run \"tree-sitter generate\": {}",
+            String::from_utf8_lossy(&generate_output.stderr)
+        );
+    }
+    info!(
+        "Finished \"tree-sitter generate\" for {}",
+        grammar_path.display()
+    );
+
+    let src_dir = out_dir.join("src");
+    if !src_dir.join("parser.c").is_file() {
+        bail!(
+            "\"tree-sitter generate\" did not produce {}",
+            src_dir.join("parser.c").display()
+        );
+    }
+    copy_grammar_sources(&grammar_path.join("src"), &src_dir)?;
+    Ok(src_dir)
+}
+
+fn read_grammar_json(path: &Path) -> anyhow::Result<serde_json::Value> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// Generates the parser of the grammar at `grammar_path` as `mode` demands
+///
+/// Returns the directory holding the sources to compile, either the committed `src` or the
+/// generated one.
+pub fn prepare_sources(
+    grammar_path: &Path,
+    options: &GenerateOptions,
+    artifact_path: &Path,
+) -> anyhow::Result<PathBuf> {
+    let src_dir = grammar_path.join("src");
+    let committed = src_dir.join("parser.c").is_file();
+    let committed_abi = if committed {
+        language_version(&src_dir)?
+    } else {
+        None
+    };
+    let abi_mismatch = options.abi.is_some_and(|abi| committed_abi != Some(abi));
+    match options.mode {
+        GenerateMode::Never if !committed => bail!(
+            "{} has no src/parser.c, generation is disabled by --generate never",
+            grammar_path.display()
+        ),
+        GenerateMode::Never if abi_mismatch => bail!(
+            "The committed parser of {} has ABI {}, but --abi {} was requested and generation is \
+             disabled by --generate never",
+            grammar_path.display(),
+            committed_abi.map_or("unknown".to_string(), |abi| abi.to_string()),
+            options.abi.unwrap_or_default()
+        ),
+        GenerateMode::Never => Ok(src_dir),
+        GenerateMode::Always => generate(grammar_path, options, artifact_path),
+        GenerateMode::IfStale if !committed => {
+            info!("{} has no src/parser.c", grammar_path.display());
+            generate(grammar_path, options, artifact_path)
+        }
+        GenerateMode::IfStale if abi_mismatch => {
+            info!(
+                "Committed parser of {} has ABI {}, regenerating",
+                grammar_path.display(),
+                committed_abi.map_or("unknown".to_string(), |abi| abi.to_string())
+            );
+            generate(grammar_path, options, artifact_path)
+        }
+        GenerateMode::IfStale if !is_installed("node") || !is_installed("tree-sitter") => {
+            info!(
+                "Node.js or the tree-sitter CLI not found, compiling the committed sources of {}",
+                grammar_path.display()
+            );
+            Ok(src_dir)
+        }
+        GenerateMode::IfStale => {
+            // Generating may fail where the committed parser compiles, e.g. when `grammar.js`
+            // requires npm dependencies that a plain checkout does not install
+            let generated_dir = match generate(grammar_path, options, artifact_path) {
+                Ok(generated_dir) => generated_dir,
+                Err(err) => {
+                    warn!(
+                        "Could not check the committed sources of {}, compiling them as is: {:#}",
+                        grammar_path.display(),
+                        err
+                    );
+                    return Ok(src_dir);
+                }
+            };
+            let generated = read_grammar_json(&generated_dir.join("grammar.json"))?;
+            let committed = src_dir.join("grammar.json");
+            if committed.is_file() && read_grammar_json(&committed)? == generated {
+                info!(
+                    "Committed sources of {} are up to date",
+                    grammar_path.display()
+                );
+                Ok(src_dir)
+            } else {
+                warn!(
+                    "{} does not match grammar.js, using the generated parser",
+                    committed.display()
+                );
+                Ok(generated_dir)
+            }
+        }
+    }
+}
+
+/// ABI version of the parser in `src_dir`, read from the `LANGUAGE_VERSION` define of `parser.c`
+pub fn language_version(src_dir: &Path) -> anyhow::Result<Option<u32>> {
+    let path = src_dir.join("parser.c");
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let file =
+        std::fs::File::open(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line?;
+        if let Some(version) = line.trim().strip_prefix("#define LANGUAGE_VERSION ") {
+            let version = version
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid LANGUAGE_VERSION in {}", path.display()))?;
+            return Ok(Some(version));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,283 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+const TREE_SITTER_JSON: &str = "tree-sitter.json";
+const PACKAGE_JSON: &str = "package.json";
+/// Kinds of queries a grammar declaration may point to
+const QUERY_KINDS: [&str; 4] = ["highlights", "injections", "locals", "tags"];
+
+/// `tree-sitter.json` of tree-sitter 0.24 and later
+#[derive(Deserialize)]
+struct TreeSitterJson {
+    grammars: Vec<GrammarJson>,
+    metadata: Option<TreeSitterJsonMetadata>,
+}
+
+#[derive(Deserialize)]
+struct TreeSitterJsonMetadata {
+    version: Option<String>,
+}
+
+/// Legacy grammar declarations in the `tree-sitter` section of `package.json`
+#[derive(Deserialize)]
+struct PackageJson {
+    name: Option<String>,
+    version: Option<String>,
+    #[serde(rename = "tree-sitter")]
+    tree_sitter: Option<Vec<GrammarJson>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct GrammarJson {
+    name: Option<String>,
+    path: Option<PathBuf>,
+    scope: Option<String>,
+    #[serde(default)]
+    file_types: Vec<String>,
+    injection_regex: Option<String>,
+    #[serde(flatten)]
+    queries: BTreeMap<String, serde_json::Value>,
+}
+
+/// Name field of a generated `src/grammar.json`
+#[derive(Deserialize)]
+struct GeneratedGrammar {
+    name: String,
+}
+
+/// What a grammar declares about itself, published in the config blob of its artifacts
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct GrammarMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub file_types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub injection_regex: Option<String>,
+    /// Query files by kind, relative to the repository root
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub queries: BTreeMap<String, Vec<String>>,
+}
+
+/// Grammar declared by a repository
+pub struct Grammar {
+    pub name: String,
+    /// Directory containing `grammar.js` and `src`
+    pub path: PathBuf,
+    /// Repository root, which declared query paths are relative to
+    pub root: PathBuf,
+    pub version: Option<String>,
+    pub metadata: GrammarMetadata,
+    /// Directory of query files replacing those of the repository
+    pub queries_dir: Option<PathBuf>,
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> anyhow::Result<Option<T>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let value = serde_json::from_slice(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(Some(value))
+}
+
+/// Name of the grammar at `path` when its declaration does not state one
+///
+/// The generated `src/grammar.json` is authoritative, otherwise the name is derived from the npm
+/// package name, given for grammars at the root of a repository, or from the directory name,
+/// e.g. `tree-sitter-markdown-inline` becomes `markdown_inline`.
+fn derive_name(path: &Path, package_name: Option<&str>) -> anyhow::Result<String> {
+    if let Some(generated) = read_json::<GeneratedGrammar>(&path.join("src").join("grammar.json"))?
+    {
+        return Ok(generated.name);
+    }
+    let directory_name = path
+        .canonicalize()?
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string());
+    let Some(name) = package_name.map(str::to_string).or(directory_name) else {
+        bail!("Cannot determine the grammar name of {}", path.display());
+    };
+    let name = name.rsplit('/').next().unwrap_or(&name);
+    Ok(name
+        .strip_prefix("tree-sitter-")
+        .unwrap_or(name)
+        .replace('-', "_"))
+}
+
+/// Query paths of a declaration, which lists either a single file or several per kind
+fn queries(declaration: &GrammarJson) -> BTreeMap<String, Vec<String>> {
+    QUERY_KINDS
+        .iter()
+        .filter_map(|kind| {
+            let paths = match declaration.queries.get(*kind)? {
+                serde_json::Value::String(path) => vec![path.clone()],
+                serde_json::Value::Array(paths) => paths
+                    .iter()
+                    .filter_map(|path| path.as_str().map(str::to_string))
+                    .collect(),
+                _ => return None,
+            };
+            Some((kind.to_string(), paths))
+        })
+        .collect()
+}
+
+/// Finds the grammars declared in `tree-sitter.json` or `package.json` of the repository at `root`
+///
+/// A repository without declarations holds a single grammar at its root if it has a `grammar.js`.
+pub fn discover(root: &Path) -> anyhow::Result<Vec<Grammar>> {
+    let (package_name, package_version, package_grammars) =
+        match read_json::<PackageJson>(&root.join(PACKAGE_JSON))? {
+            Some(package) => (
+                package.name,
+                package.version,
+                package.tree_sitter.unwrap_or_default(),
+            ),
+            None => (None, None, Vec::new()),
+        };
+    let (version, declarations) = match read_json::<TreeSitterJson>(&root.join(TREE_SITTER_JSON))? {
+        Some(tree_sitter_json) => (
+            tree_sitter_json
+                .metadata
+                .and_then(|metadata| metadata.version)
+                .or(package_version),
+            tree_sitter_json.grammars,
+        ),
+        None => (package_version, package_grammars),
+    };
+    if declarations.is_empty() {
+        if !root.join("grammar.js").is_file() {
+            return Ok(Vec::new());
+        }
+        return Ok(vec![Grammar {
+            name: derive_name(root, package_name.as_deref())?,
+            path: root.to_path_buf(),
+            root: root.to_path_buf(),
+            version,
+            metadata: GrammarMetadata::default(),
+            queries_dir: None,
+        }]);
+    }
+
+    declarations
+        .into_iter()
+        .map(|declaration| {
+            let path = match &declaration.path {
+                Some(path) if path != Path::new(".") => root.join(path),
+                _ => root.to_path_buf(),
+            };
+            let name = match &declaration.name {
+                Some(name) => name.clone(),
+                None => derive_name(&path, package_name.as_deref().filter(|_| path == root))?,
+            };
+            let metadata = GrammarMetadata {
+                queries: queries(&declaration),
+                scope: declaration.scope,
+                file_types: declaration.file_types,
+                injection_regex: declaration.injection_regex,
+            };
+            Ok(Grammar {
+                name,
+                path,
+                root: root.to_path_buf(),
+                version: version.clone(),
+                metadata,
+                queries_dir: None,
+            })
+        })
+        .collect()
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Grammars of the repository at `root`, limited to those in `subpath` if given
+///
+/// Grammars in a subdirectory are usually declared at the repository root, so the declarations
+/// of `subpath` itself are only consulted when the root declares none for it.
+pub fn discover_in(root: &Path, subpath: Option<&Path>) -> anyhow::Result<Vec<Grammar>> {
+    let grammars = discover(root)?;
+    let Some(subpath) = subpath else {
+        return Ok(grammars);
+    };
+    let path = root.join(subpath);
+    let grammars: Vec<Grammar> = grammars
+        .into_iter()
+        .filter(|grammar| same_path(&grammar.path, &path))
+        .collect();
+    if grammars.is_empty() {
+        discover(&path)
+    } else {
+        Ok(grammars)
+    }
+}
+
+/// Selects the grammar to build from the repository at `root`
+///
+/// Without a `name` the repository has to declare exactly one grammar. A `name` the repository
+/// does not declare is used for the grammar at `root` or `subpath` itself, if there is one.
+pub fn select(root: &Path, subpath: Option<&Path>, name: Option<&str>) -> anyhow::Result<Grammar> {
+    let mut grammars = discover_in(root, subpath)?;
+    let path = match subpath {
+        Some(subpath) => root.join(subpath),
+        None => root.to_path_buf(),
+    };
+    let names: Vec<&str> = grammars
+        .iter()
+        .map(|grammar| grammar.name.as_str())
+        .collect();
+    let names = names.join(", ");
+
+    let Some(name) = name else {
+        return match grammars.len() {
+            0 => bail!("No grammar found in {}", path.display()),
+            1 => Ok(grammars.remove(0)),
+            _ => bail!(
+                "{} declares several grammars ({}), pass --grammar-name",
+                path.display(),
+                names
+            ),
+        };
+    };
+    if let Some(index) = grammars.iter().position(|grammar| grammar.name == name) {
+        return Ok(grammars.remove(index));
+    }
+    if !path.join("grammar.js").is_file() {
+        if grammars.is_empty() {
+            bail!("No grammar found in {}", path.display());
+        }
+        bail!(
+            "No grammar {} in {}, it declares {}",
+            name,
+            path.display(),
+            names
+        );
+    }
+    // Keep what the repository declares about the grammar, just under a different name
+    let declared = grammars
+        .into_iter()
+        .find(|grammar| same_path(&grammar.path, &path));
+    Ok(Grammar {
+        name: name.to_string(),
+        version: declared
+            .as_ref()
+            .and_then(|grammar| grammar.version.clone()),
+        metadata: declared.map(|grammar| grammar.metadata).unwrap_or_default(),
+        path,
+        root: root.to_path_buf(),
+        queries_dir: None,
+    })
+}
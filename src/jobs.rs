@@ -0,0 +1,127 @@
+use log::{Level, Log, Metadata, Record};
+use std::{
+    cell::RefCell,
+    sync::{Condvar, Mutex, OnceLock},
+    thread::ScopedJoinHandle,
+};
+
+static SLOTS: OnceLock<Slots> = OnceLock::new();
+static LOGGER: OnceLock<JobLogger> = OnceLock::new();
+/// Held while writing log output so that the records of one job stay together
+static OUTPUT: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<(Level, String, String)>>> = const { RefCell::new(None) };
+}
+
+/// Counting semaphore over the external processes that may run at once
+struct Slots {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Limits the number of concurrently running compilers and generators to `jobs`
+pub fn init(jobs: usize) {
+    let _ = SLOTS.set(Slots {
+        available: Mutex::new(jobs.max(1)),
+        released: Condvar::new(),
+    });
+}
+
+/// Permission to run one external process, returned to the pool when dropped
+pub struct Slot(Option<&'static Slots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(slots) = self.0 {
+            *slots.available.lock().unwrap() += 1;
+            slots.released.notify_one();
+        }
+    }
+}
+
+/// Waits until the pool has room for another process, unbounded before [`init`]
+pub fn acquire() -> Slot {
+    let slots = SLOTS.get();
+    if let Some(slots) = slots {
+        let available = slots.available.lock().unwrap();
+        let mut available = slots
+            .released
+            .wait_while(available, |available| *available == 0)
+            .unwrap();
+        *available -= 1;
+    }
+    Slot(slots)
+}
+
+/// Logger holding back the records of jobs run through [`logged`]
+struct JobLogger(Box<dyn Log>);
+
+impl Log for JobLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let record = CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+            Some(captured) => {
+                captured.push((
+                    record.level(),
+                    record.target().to_string(),
+                    record.args().to_string(),
+                ));
+                None
+            }
+            None => Some(record),
+        });
+        if let Some(record) = record {
+            let _output = OUTPUT.lock().unwrap();
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Installs `logger` as the global logger, wrapped to support [`logged`]
+pub fn init_logger(logger: Box<dyn Log>, max_level: log::LevelFilter) {
+    let logger = LOGGER.get_or_init(|| JobLogger(logger));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Runs `f` on the current thread and writes everything it logged as one block afterwards
+pub fn logged<T>(f: impl FnOnce() -> T) -> T {
+    let outer = CAPTURED.with(|captured| captured.replace(Some(Vec::new())));
+    let result = f();
+    let records = CAPTURED
+        .with(|captured| captured.replace(outer))
+        .unwrap_or_default();
+
+    if let Some(logger) = LOGGER.get() {
+        let _output = OUTPUT.lock().unwrap();
+        for (level, target, message) in records {
+            logger.0.log(
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(level)
+                    .target(&target)
+                    .build(),
+            );
+        }
+    }
+    result
+}
+
+/// Waits for a scoped thread, propagating its panic
+pub fn join<T>(handle: ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
@@ -0,0 +1,500 @@
+use crate::target;
+use anyhow::{bail, Context};
+use std::path::Path;
+
+/// Bounds checked reads from an object file of either byte order
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> anyhow::Result<[u8; N]> {
+        let bytes = offset
+            .checked_add(N)
+            .and_then(|end| self.data.get(offset..end))
+            .context("Truncated object file")?;
+        Ok(bytes.try_into().expect("slice has length N"))
+    }
+
+    fn u8(&self, offset: usize) -> anyhow::Result<u8> {
+        Ok(self.bytes::<1>(offset)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> anyhow::Result<usize> {
+        let bytes = self.bytes(offset)?;
+        Ok(usize::from(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }))
+    }
+
+    fn u32(&self, offset: usize) -> anyhow::Result<usize> {
+        let bytes = self.bytes(offset)?;
+        let value = if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        };
+        Ok(usize::try_from(value)?)
+    }
+
+    fn u64(&self, offset: usize) -> anyhow::Result<usize> {
+        let bytes = self.bytes(offset)?;
+        let value = if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        };
+        Ok(usize::try_from(value)?)
+    }
+
+    fn c_str(&self, offset: usize) -> anyhow::Result<&str> {
+        let bytes = self.data.get(offset..).context("Truncated object file")?;
+        let end = bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .context("Unterminated symbol name")?;
+        Ok(std::str::from_utf8(&bytes[..end])?)
+    }
+}
+
+/// `base + delta`, failing instead of overflowing on malformed headers
+fn add(base: usize, delta: usize) -> anyhow::Result<usize> {
+    base.checked_add(delta).context("Truncated object file")
+}
+
+/// `base + index * size`, failing instead of overflowing on malformed headers
+fn offset(base: usize, index: usize, size: usize) -> anyhow::Result<usize> {
+    add(
+        base,
+        index.checked_mul(size).context("Truncated object file")?,
+    )
+}
+
+/// Defined global symbols of the dynamic symbol table of an ELF shared object
+fn elf_exports(data: &[u8]) -> anyhow::Result<Vec<String>> {
+    let wide = data.get(4) == Some(&2);
+    let reader = Reader {
+        data,
+        big_endian: data.get(5) == Some(&2),
+    };
+    let (section_offset, entry_size, count) = if wide {
+        (reader.u64(0x28)?, reader.u16(0x3a)?, reader.u16(0x3c)?)
+    } else {
+        (reader.u32(0x20)?, reader.u16(0x2e)?, reader.u16(0x30)?)
+    };
+    // Offset, size and linked section of a section header
+    let section = |index: usize| -> anyhow::Result<(usize, usize, usize)> {
+        let header = offset(section_offset, index, entry_size)?;
+        Ok(if wide {
+            (
+                reader.u64(add(header, 24)?)?,
+                reader.u64(add(header, 32)?)?,
+                reader.u32(add(header, 40)?)?,
+            )
+        } else {
+            (
+                reader.u32(add(header, 16)?)?,
+                reader.u32(add(header, 20)?)?,
+                reader.u32(add(header, 24)?)?,
+            )
+        })
+    };
+
+    const SHT_DYNSYM: usize = 11;
+    let mut exports = Vec::new();
+    for index in 0..count {
+        if reader.u32(add(offset(section_offset, index, entry_size)?, 4)?)? != SHT_DYNSYM {
+            continue;
+        }
+        let (symbols, size, link) = section(index)?;
+        let (strings, _, _) = section(link)?;
+        let symbol_size = if wide { 24 } else { 16 };
+        for symbol in (symbols..add(symbols, size)?).step_by(symbol_size) {
+            let (info, section_index) = if wide {
+                (reader.u8(add(symbol, 4)?)?, reader.u16(add(symbol, 6)?)?)
+            } else {
+                (reader.u8(add(symbol, 12)?)?, reader.u16(add(symbol, 14)?)?)
+            };
+            // Global or weak binding, defined in a section of this object
+            if matches!(info >> 4, 1 | 2) && section_index != 0 {
+                let name = add(strings, reader.u32(symbol)?)?;
+                exports.push(reader.c_str(name)?.to_string());
+            }
+        }
+    }
+    Ok(exports)
+}
+
+/// External symbols defined by a Mach-O dylib, or by each slice of a universal binary
+fn mach_o_exports(data: &[u8]) -> anyhow::Result<Vec<String>> {
+    let reader = Reader {
+        data,
+        big_endian: true,
+    };
+    if reader.u32(0)? == 0xcafe_babe {
+        let mut exports = Vec::new();
+        for index in 0..reader.u32(4)? {
+            let arch = offset(8, index, 20)?;
+            let (start, size) = (reader.u32(add(arch, 8)?)?, reader.u32(add(arch, 12)?)?);
+            let slice = data
+                .get(start..add(start, size)?)
+                .context("Truncated universal binary")?;
+            // Slices are thin, a nested universal binary could recurse forever
+            if slice.starts_with(&[0xca, 0xfe, 0xba, 0xbe]) {
+                bail!("Nested universal binary");
+            }
+            exports.extend(mach_o_exports(slice)?);
+        }
+        return Ok(exports);
+    }
+
+    let reader = Reader {
+        data,
+        big_endian: false,
+    };
+    let wide = reader.u32(0)? == 0xfeed_facf;
+    let mut command = if wide { 32 } else { 28 };
+    const LC_SYMTAB: usize = 0x2;
+    let mut exports = Vec::new();
+    for _ in 0..reader.u32(16)? {
+        if reader.u32(command)? == LC_SYMTAB {
+            let (symbols, count) = (
+                reader.u32(add(command, 8)?)?,
+                reader.u32(add(command, 12)?)?,
+            );
+            let strings = reader.u32(add(command, 16)?)?;
+            let symbol_size = if wide { 16 } else { 12 };
+            for index in 0..count {
+                let symbol = offset(symbols, index, symbol_size)?;
+                let kind = reader.u8(add(symbol, 4)?)?;
+                // External and defined in a section
+                if kind & 0x01 != 0 && kind & 0x0e == 0x0e {
+                    let name = reader.c_str(add(strings, reader.u32(symbol)?)?)?;
+                    exports.push(name.strip_prefix('_').unwrap_or(name).to_string());
+                }
+            }
+        }
+        let size = reader.u32(add(command, 4)?)?;
+        if size < 8 {
+            bail!("Invalid load command size {}", size);
+        }
+        command = add(command, size)?;
+    }
+    Ok(exports)
+}
+
+/// Names in the export directory of a PE DLL
+fn pe_exports(data: &[u8]) -> anyhow::Result<Vec<String>> {
+    let reader = Reader {
+        data,
+        big_endian: false,
+    };
+    let pe_header = reader.u32(0x3c)?;
+    if reader.bytes::<4>(pe_header)? != *b"PE\0\0" {
+        bail!("Missing PE signature");
+    }
+    let coff = add(pe_header, 4)?;
+    let section_count = reader.u16(add(coff, 2)?)?;
+    let optional = add(coff, 20)?;
+    let sections = add(optional, reader.u16(add(coff, 16)?)?)?;
+    let directories = match reader.u16(optional)? {
+        0x10b => add(optional, 96)?,
+        0x20b => add(optional, 112)?,
+        magic => bail!("Unknown PE optional header magic {:#x}", magic),
+    };
+    let export_rva = reader.u32(directories)?;
+    if export_rva == 0 {
+        return Ok(Vec::new());
+    }
+    // Translates a relative virtual address to a file offset through the section table
+    let file_offset = |rva: usize| -> anyhow::Result<usize> {
+        for index in 0..section_count {
+            let section = offset(sections, index, 40)?;
+            let address = reader.u32(add(section, 12)?)?;
+            let size = reader.u32(add(section, 16)?)?;
+            if (address..add(address, size)?).contains(&rva) {
+                return add(rva - address, reader.u32(add(section, 20)?)?);
+            }
+        }
+        bail!("Address {:#x} is outside of every section", rva)
+    };
+
+    let export_directory = file_offset(export_rva)?;
+    let names = file_offset(reader.u32(add(export_directory, 32)?)?)?;
+    (0..reader.u32(add(export_directory, 24)?)?)
+        .map(|index| {
+            Ok(reader
+                .c_str(file_offset(reader.u32(offset(names, index, 4)?)?)?)?
+                .to_string())
+        })
+        .collect()
+}
+
+/// Symbols exported by the shared library `data`, which may be ELF, Mach-O or PE
+fn exported_symbols(data: &[u8]) -> anyhow::Result<Vec<String>> {
+    match data.get(..4) {
+        Some([0x7f, b'E', b'L', b'F']) => elf_exports(data),
+        Some([0xcf | 0xce, 0xfa, 0xed, 0xfe] | [0xca, 0xfe, 0xba, 0xbe]) => mach_o_exports(data),
+        Some([b'M', b'Z', ..]) => pe_exports(data),
+        _ => bail!("Not an ELF, Mach-O or PE shared library"),
+    }
+}
+
+/// Loads the host library at `path` and reads the ABI version of the language it exports
+fn load_language_version(path: &Path, symbol: &str) -> anyhow::Result<u32> {
+    // SAFETY: the library was just compiled from generated parser sources, whose initializers
+    // have no side effects, and the language function takes no arguments
+    unsafe {
+        let library = libloading::Library::new(path)
+            .with_context(|| format!("Failed to load {}", path.display()))?;
+        let language: libloading::Symbol<unsafe extern "C" fn() -> *const u32> = library
+            .get(symbol.as_bytes())
+            .with_context(|| format!("{} does not export {}", path.display(), symbol))?;
+        // The ABI version is the first field of `TSLanguage` in every ABI
+        let language = language();
+        if language.is_null() {
+            bail!("{} of {} returned no language", symbol, path.display());
+        }
+        Ok(*language)
+    }
+}
+
+/// Checks that the shared library built for `target` exports the language function of
+/// `grammar_name`
+///
+/// Libraries for the host are loaded to read the ABI version of the language they return, for
+/// other targets only the symbol table is inspected and `None` is returned.
+pub fn check(path: &Path, target: &str, grammar_name: &str) -> anyhow::Result<Option<u32>> {
+    let symbol = format!("tree_sitter_{}", grammar_name);
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let exports = exported_symbols(&data)
+        .with_context(|| format!("Failed to read the symbol table of {}", path.display()))?;
+    if !exports.contains(&symbol) {
+        let languages: Vec<&str> = exports
+            .iter()
+            .filter(|export| {
+                export.starts_with("tree_sitter_") && !export.contains("_external_scanner_")
+            })
+            .map(String::as_str)
+            .collect();
+        bail!(
+            "{} does not export {}, it exports {}",
+            path.display(),
+            symbol,
+            if languages.is_empty() {
+                "no language".to_string()
+            } else {
+                languages.join(", ")
+            }
+        );
+    }
+    if target != target::HOST {
+        return Ok(None);
+    }
+    load_language_version(path, &symbol).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `bytes` at `offset` of `data`, growing it as needed
+    fn put(data: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        if data.len() < offset + bytes.len() {
+            data.resize(offset + bytes.len(), 0);
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// 64-bit little endian ELF with a dynamic symbol table of `(name, defined)` globals
+    fn elf(symbols: &[(&str, bool)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        put(&mut data, 0, &[0x7f, b'E', b'L', b'F', 2, 1]);
+        // Null section, `.dynsym` and `.dynstr`
+        put(&mut data, 0x28, &64u64.to_le_bytes());
+        put(&mut data, 0x3a, &64u16.to_le_bytes());
+        put(&mut data, 0x3c, &3u16.to_le_bytes());
+        let (dynsym, dynstr) = (256, 256 + 24 * (symbols.len() + 1));
+        put(&mut data, 128 + 4, &11u32.to_le_bytes());
+        put(&mut data, 128 + 24, &(dynsym as u64).to_le_bytes());
+        put(
+            &mut data,
+            128 + 32,
+            &(24 * (symbols.len() as u64 + 1)).to_le_bytes(),
+        );
+        put(&mut data, 128 + 40, &2u32.to_le_bytes());
+        put(&mut data, 192 + 24, &(dynstr as u64).to_le_bytes());
+        // The first symbol is the null symbol
+        let mut name = dynstr + 1;
+        for (index, (symbol, defined)) in symbols.iter().enumerate() {
+            let entry = dynsym + 24 * (index + 1);
+            put(&mut data, entry, &((name - dynstr) as u32).to_le_bytes());
+            put(&mut data, entry + 4, &[0x12]);
+            put(&mut data, entry + 6, &u16::from(*defined).to_le_bytes());
+            put(&mut data, name, symbol.as_bytes());
+            name += symbol.len() + 1;
+        }
+        put(&mut data, name, &[0]);
+        data
+    }
+
+    /// 64-bit Mach-O dylib with a symbol table of `(name, defined)` externals
+    fn mach_o(symbols: &[(&str, bool)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        put(&mut data, 0, &0xfeed_facfu32.to_le_bytes());
+        put(&mut data, 16, &1u32.to_le_bytes());
+        let (nlist, strings) = (56, 56 + 16 * symbols.len());
+        put(&mut data, 32, &2u32.to_le_bytes());
+        put(&mut data, 36, &24u32.to_le_bytes());
+        put(&mut data, 40, &(nlist as u32).to_le_bytes());
+        put(&mut data, 44, &(symbols.len() as u32).to_le_bytes());
+        put(&mut data, 48, &(strings as u32).to_le_bytes());
+        let mut name = strings + 1;
+        for (index, (symbol, defined)) in symbols.iter().enumerate() {
+            let entry = nlist + 16 * index;
+            put(&mut data, entry, &((name - strings) as u32).to_le_bytes());
+            put(&mut data, entry + 4, &[if *defined { 0x0f } else { 0x01 }]);
+            put(&mut data, name, format!("_{}\0", symbol).as_bytes());
+            name += symbol.len() + 2;
+        }
+        data
+    }
+
+    /// Universal binary of a single `slice`
+    fn universal(slice: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        put(&mut data, 0, &0xcafe_babeu32.to_be_bytes());
+        put(&mut data, 4, &1u32.to_be_bytes());
+        put(&mut data, 16, &32u32.to_be_bytes());
+        put(&mut data, 20, &(slice.len() as u32).to_be_bytes());
+        put(&mut data, 32, slice);
+        data
+    }
+
+    /// PE32+ DLL exporting `names`, with one section mapped at 0x1000 holding the export directory
+    fn pe(names: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        put(&mut data, 0, b"MZ");
+        put(&mut data, 0x3c, &0x40u32.to_le_bytes());
+        put(&mut data, 0x40, b"PE\0\0");
+        put(&mut data, 0x46, &1u16.to_le_bytes());
+        put(&mut data, 0x54, &240u16.to_le_bytes());
+        put(&mut data, 0x58, &0x20bu16.to_le_bytes());
+        put(&mut data, 0x58 + 112, &0x1000u32.to_le_bytes());
+        let (section, raw) = (0x58 + 240, 0x200);
+        put(&mut data, section + 12, &0x1000u32.to_le_bytes());
+        put(&mut data, section + 16, &0x1000u32.to_le_bytes());
+        put(&mut data, section + 20, &(raw as u32).to_le_bytes());
+        put(&mut data, raw + 24, &(names.len() as u32).to_le_bytes());
+        put(&mut data, raw + 32, &0x1028u32.to_le_bytes());
+        let mut name = 0x28 + 4 * names.len();
+        for (index, export) in names.iter().enumerate() {
+            put(
+                &mut data,
+                raw + 0x28 + 4 * index,
+                &(0x1000 + name as u32).to_le_bytes(),
+            );
+            put(&mut data, raw + name, format!("{}\0", export).as_bytes());
+            name += export.len() + 1;
+        }
+        data
+    }
+
+    #[test]
+    fn reads_exports_of_every_format() {
+        let symbols = [
+            ("tree_sitter_demo", true),
+            ("tree_sitter_demo_external_scanner_create", true),
+            ("malloc", false),
+        ];
+        let exports = [
+            "tree_sitter_demo",
+            "tree_sitter_demo_external_scanner_create",
+        ];
+        assert_eq!(exported_symbols(&elf(&symbols)).unwrap(), exports);
+        assert_eq!(exported_symbols(&mach_o(&symbols)).unwrap(), exports);
+        assert_eq!(
+            exported_symbols(&universal(&mach_o(&symbols))).unwrap(),
+            exports
+        );
+        assert_eq!(exported_symbols(&pe(&exports)).unwrap(), exports);
+    }
+
+    #[test]
+    fn rejects_malformed_object_files() {
+        let mut elf = elf(&[("tree_sitter_demo", true)]);
+        put(&mut elf, 0x28, &u64::MAX.to_le_bytes());
+        let mut mach_o = mach_o(&[("tree_sitter_demo", true)]);
+        put(&mut mach_o, 36, &0u32.to_le_bytes());
+        // A slice spanning the whole universal binary contains itself
+        let mut nested = universal(&[]);
+        put(&mut nested, 16, &0u32.to_be_bytes());
+        put(&mut nested, 20, &32u32.to_be_bytes());
+        let mut pe = pe(&["tree_sitter_demo"]);
+        put(&mut pe, 0x58 + 240 + 12, &u32::MAX.to_le_bytes());
+        for data in [
+            &elf[..],
+            &mach_o,
+            &nested,
+            &pe,
+            &universal(&[])[..4],
+            b"\x7fELF",
+            b"MZ",
+            b"",
+        ] {
+            assert!(exported_symbols(data).is_err());
+        }
+    }
+
+    #[test]
+    fn checks_the_language_symbol() {
+        let path = std::env::temp_dir().join(format!("library-check-{}.so", std::process::id()));
+        std::fs::write(
+            &path,
+            elf(&[("tree_sitter_other", true), ("tree_sitter_demo", false)]),
+        )
+        .unwrap();
+        // Symbol tables of foreign targets are only inspected
+        let target = "aarch64-unknown-linux-gnu";
+        assert_eq!(check(&path, target, "other").unwrap(), None);
+        let error = check(&path, target, "demo").unwrap_err().to_string();
+        assert!(error.ends_with("does not export tree_sitter_demo, it exports tree_sitter_other"));
+
+        std::fs::write(
+            &path,
+            elf(&[("tree_sitter_demo_external_scanner_create", true)]),
+        )
+        .unwrap();
+        let error = check(&path, target, "demo").unwrap_err().to_string();
+        assert!(error.ends_with("does not export tree_sitter_demo, it exports no language"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn loads_the_language_of_host_libraries() {
+        let dir = std::env::temp_dir().join(format!("library-load-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("parser.c");
+        std::fs::write(
+            &source,
+            "static const unsigned language[] = {14};\n\
+             const void *tree_sitter_demo(void) { return language; }\n",
+        )
+        .unwrap();
+        let library = dir.join("demo.so");
+        let status = std::process::Command::new("cc")
+            .args(["-shared", "-fPIC", "-o"])
+            .arg(&library)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(check(&library, target::HOST, "demo").unwrap(), Some(14));
+        assert!(check(&library, target::HOST, "other").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,153 +1,313 @@
 use anyhow::{bail, Context};
+use build::{ArtifactKind, BuildArgs, BuildOptions};
 use clap::Parser;
 use log::info;
-use std::{
-    path::{Path, PathBuf},
-    process::Command,
-};
+use oci_distribution::Reference;
+use registry::{AbiRange, RegistryArgs};
+use std::path::PathBuf;
+
+mod batch;
+mod build;
+mod cache;
+mod compile;
+mod corpus;
+mod generate;
+mod grammar;
+mod jobs;
+mod library;
+mod queries;
+mod registry;
+mod source;
+mod target;
 
 #[derive(clap::Parser)]
 #[clap(author, version)]
 struct Args {
-    /// Path to parser library root
-    #[clap(long, default_value = ".")]
-    grammar_path: PathBuf,
+    /// Registry repository prefix, e.g. `registry.local/parsers`
+    #[clap(long, global = true, env = "TS_REGISTRY")]
+    registry: Option<String>,
     /// Path where intermediate artifacts should be placed
-    #[clap(short, long, default_value = "./artifacts")]
+    #[clap(short, long, global = true, default_value = "./artifacts")]
     artifact_path: PathBuf,
-    /// Grammar name
-    #[clap(short, long)]
-    grammar_name: String,
+    /// Log level, overridden by `RUST_LOG`
+    #[clap(long, global = true, default_value = "info")]
+    log_level: log::LevelFilter,
+    #[clap(flatten)]
+    registry_args: RegistryArgs,
+
+    #[clap(subcommand)]
+    command: Subcommand,
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Generate and compile a parser into the artifact directory
+    Build(BuildArgs),
+    /// Build every grammar listed in a manifest file
+    BuildAll(BuildAllArgs),
+    /// Publish the parser in the artifact directory to the registry
+    Push(PushArgs),
+    /// Fetch a parser from the registry and install it locally
+    Pull(PullArgs),
+    /// Show the metadata of a published parser
+    Inspect(InspectArgs),
+    /// List published grammars, or the versions of one grammar
+    List(ListArgs),
+}
+
+#[derive(clap::Args)]
+struct BuildAllArgs {
+    /// TOML or JSON file listing the grammars under `[[grammars]]`
+    manifest: PathBuf,
+    #[clap(flatten)]
+    options: BuildOptions,
+}
 
-    /// Compilation target
-    #[clap(short, long, default_value = "x86_64-unknown-linux-gnu")]
+#[derive(clap::Args)]
+struct PushArgs {
+    /// Version under which the grammar is published, defaults to the version it declares or
+    /// `latest`
+    #[clap(long)]
+    grammar_version: Option<String>,
+    /// Keep the parsers of an existing index at the same tag that this build does not replace,
+    /// to publish parsers for several ABIs under one version
+    #[clap(long)]
+    merge: bool,
+    /// ABI versions the parser may be published with
+    #[clap(flatten)]
+    abi: AbiRange,
+}
+
+#[derive(clap::Args)]
+struct PullArgs {
+    /// Parser reference, either `<grammar>:<version>` relative to `--registry` or a full reference
+    reference: String,
+    /// Directory into which the parser library is installed
+    #[clap(short, long, default_value = ".")]
+    parser_dir: PathBuf,
+    /// Target for which the parser library is selected
+    #[clap(short, long, default_value = target::HOST)]
     target: String,
+    /// Kind of artifact to install
+    #[clap(long, default_value = "c-dynlib")]
+    kind: ArtifactKind,
+    /// Directory into which the query files are installed, below a directory named after the
+    /// grammar
+    #[clap(long)]
+    queries_dir: Option<PathBuf>,
+    /// Only install the query files, not the parser library
+    #[clap(long, requires = "queries_dir")]
+    queries_only: bool,
+    /// ABI versions the installed parser has to be compatible with
+    #[clap(flatten)]
+    abi: AbiRange,
 }
 
-fn compile_c_dynlib(
-    src_dir: &Path,
-    dst_dir: &Path,
-    dst_name: &str,
-    target: &str,
-) -> anyhow::Result<()> {
-    std::fs::create_dir_all(dst_dir)?;
-    let parser_path = src_dir.join("parser.c");
-    let c_scanner = src_dir.join("scanner.c");
-    let cpp_scanner = src_dir.join("scanner.cc");
-    let library_path = dst_dir.join(dst_name);
-
-    let scanner_path = if c_scanner.is_file() {
-        Some(c_scanner)
-    } else if cpp_scanner.is_file() {
-        Some(cpp_scanner)
-    } else {
-        None
-    };
+#[derive(clap::Args)]
+struct InspectArgs {
+    /// Parser reference, either `<grammar>:<version>` relative to `--registry` or a full reference
+    reference: String,
+}
+
+#[derive(clap::Args)]
+struct ListArgs {
+    /// Grammar whose versions should be listed
+    grammar_name: Option<String>,
+}
 
-    let header_path = src_dir;
-    let mut config = cc::Build::new();
-    config
-        .cpp(true)
-        .opt_level(2)
-        .cargo_metadata(false)
-        .target(target)
-        .host(target);
-    let compiler = config.get_compiler();
-    let mut command = Command::new(compiler.path());
-    for (key, value) in compiler.env() {
-        command.env(key, value);
+impl Args {
+    fn registry(&self) -> anyhow::Result<&str> {
+        self.registry
+            .as_deref()
+            .context("No registry configured, pass --registry or set TS_REGISTRY")
     }
 
-    if cfg!(windows) {
-        command.args(&["/nologo", "/LD", "/I"]).arg(header_path);
-        command.arg("/O2");
-        command.arg(parser_path);
-        if let Some(scanner_path) = scanner_path.as_ref() {
-            command.arg(scanner_path);
-        }
-        command
-            .arg("/link")
-            .arg(format!("/out:{}", library_path.to_str().unwrap()));
-    } else {
-        command
-            .arg("-shared")
-            .arg("-fPIC")
-            .arg("-fno-exceptions")
-            .arg("-g")
-            .arg("-I")
-            .arg(header_path)
-            .arg("-o")
-            .arg(&library_path)
-            .arg("-O2");
-
-        if let Some(scanner_path) = scanner_path.as_ref() {
-            if scanner_path.extension() == Some("c".as_ref()) {
-                command.arg("-xc").arg("-std=c99").arg(scanner_path);
-            } else {
-                command.arg(scanner_path);
-            }
+    /// Resolves `reference` relative to `--registry` unless it already names a repository
+    fn reference(&self, reference: &str) -> anyhow::Result<Reference> {
+        let reference = if reference.contains('/') {
+            reference.to_string()
+        } else {
+            format!("{}/{}", self.registry()?, reference)
+        };
+        Ok(reference.parse()?)
+    }
+}
+
+fn build(args: &Args, build_args: &BuildArgs) -> anyhow::Result<()> {
+    jobs::init(build_args.options.jobs());
+    let (grammar_path, git) = match &build_args.git {
+        Some(url) => {
+            let checkout = args.artifact_path.join("checkout");
+            let git = source::fetch(url, build_args.revision.as_deref(), &checkout)?;
+            (checkout, Some(git))
         }
-        command.arg("-xc").arg(parser_path);
+        None => (build_args.grammar_path.clone(), None),
+    };
+    let mut grammar = grammar::select(
+        &grammar_path,
+        build_args.subpath.as_deref(),
+        build_args.grammar_name.as_deref(),
+    )?;
+    grammar.queries_dir = build_args.queries.clone();
+    let record = build::build(
+        &grammar,
+        git.as_ref(),
+        &build_args.options,
+        &args.artifact_path,
+    )?;
+    for library in record
+        .targets
+        .iter()
+        .flat_map(|target| [&target.library, &target.static_library])
+        .flatten()
+    {
+        info!("Built {}", library.display());
+    }
+    if let Some(wasm) = &record.wasm {
+        info!("Built {}", wasm.display());
     }
 
-    let output = command
-        .output()
-        .with_context(|| "Failed to execute C compiler")?;
-    if !output.status.success() {
-        bail!(
-            "Parser compilation failed.\nStdout: {}\nStderr: {}",
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        );
+    Ok(())
+}
+
+fn build_all(args: &Args, build_all_args: &BuildAllArgs) -> anyhow::Result<()> {
+    let manifest = batch::GrammarManifest::load(&build_all_args.manifest)?;
+    jobs::init(build_all_args.options.jobs());
+    let results = batch::build_all(&manifest, &build_all_args.options, &args.artifact_path);
+
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .map(|(name, _)| name.as_str())
+        .collect();
+    println!(
+        "{} succeeded, {} failed",
+        results.len() - failed.len(),
+        failed.len()
+    );
+    for (name, result) in &results {
+        match result {
+            Ok(_) => println!("  ok      {}", name),
+            Err(err) => println!("  FAILED  {}: {:#}", name, err),
+        }
+    }
+    if !failed.is_empty() {
+        bail!("Failed to build {}", failed.join(", "));
     }
 
-    let output = command
-        .output()
-        .with_context(|| "Failed to execute C compiler")?;
-    if !output.status.success() {
-        bail!(
-            "Parser compilation failed.\nStdout: {}\nStderr: {}",
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        );
+    Ok(())
+}
+
+async fn push(args: &Args, push_args: &PushArgs) -> anyhow::Result<()> {
+    let record = build::BuildRecord::load(&args.artifact_path)?;
+    record.check_corpus()?;
+    if push_args.abi.is_bounded() && !push_args.abi.contains(record.language_version) {
+        match record.language_version {
+            Some(abi) => bail!(
+                "{} has ABI {}, outside of the accepted range {}",
+                record.name,
+                abi,
+                push_args.abi
+            ),
+            None => bail!(
+                "ABI of {} is unknown, cannot check it against the accepted range {}",
+                record.name,
+                push_args.abi
+            ),
+        }
     }
+    let version = push_args
+        .grammar_version
+        .as_deref()
+        .or(record.version.as_deref())
+        .unwrap_or("latest");
+    let reference = args.reference(&format!("{}:{}", record.name, version))?;
+    let url = registry::push(
+        &args.registry_args,
+        &reference,
+        version,
+        &record,
+        push_args.merge,
+    )
+    .await?;
+    info!("Pushed {}", url);
+
     Ok(())
 }
 
-fn generate_artifacts(args: Args) -> anyhow::Result<()> {
-    std::fs::create_dir_all(&args.artifact_path)?;
-    let generate_output = Command::new("tree-sitter")
-        .args([
-            "generate",
-            &args.grammar_path.join("grammar.js").to_string_lossy(),
-        ])
-        .current_dir(&args.artifact_path)
-        .output()?;
-    if !generate_output.status.success() {
-        bail!(
-            "Failed to run \"tree-sitter generate\": {}",
-            String::from_utf8_lossy(&generate_output.stderr)
-        );
+async fn pull(args: &Args, pull_args: &PullArgs) -> anyhow::Result<()> {
+    let reference = args.reference(&pull_args.reference)?;
+    if !pull_args.queries_only {
+        let library_path = registry::pull(
+            &args.registry_args,
+            &reference,
+            &pull_args.target,
+            pull_args.kind,
+            &pull_args.abi,
+            &pull_args.parser_dir,
+        )
+        .await?;
+        info!("Installed {}", library_path.display());
+    }
+    if let Some(queries_dir) = &pull_args.queries_dir {
+        let queries_path = registry::pull_queries(
+            &args.registry_args,
+            &reference,
+            &pull_args.target,
+            &pull_args.abi,
+            queries_dir,
+        )
+        .await?;
+        info!("Installed queries into {}", queries_path.display());
     }
-    info!("Finished \"tree-sitter generate\"");
-
-    let c_dynlib_path = args.artifact_path.join("c-dynlib");
-    compile_c_dynlib(
-        &args.grammar_path.join("src"),
-        &c_dynlib_path,
-        &format!("{}.so", args.grammar_name),
-        &args.target,
-    )?;
-    info!("Finished compilation of dynamic C library");
 
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init();
-    let args = Args::parse();
+async fn inspect(args: &Args, inspect_args: &InspectArgs) -> anyhow::Result<()> {
+    let reference = args.reference(&inspect_args.reference)?;
+    for (manifest, grammar) in registry::inspect(&args.registry_args, &reference).await? {
+        println!("{}", serde_json::to_string_pretty(&grammar)?);
+        for layer in manifest.layers {
+            println!("{}", layer);
+        }
+    }
 
-    generate_artifacts(args)?;
+    Ok(())
+}
+
+async fn list(args: &Args, list_args: &ListArgs) -> anyhow::Result<()> {
+    let entries = match &list_args.grammar_name {
+        Some(grammar_name) => {
+            registry::list_tags(&args.registry_args, args.registry()?, grammar_name).await?
+        }
+        None => registry::list_grammars(&args.registry_args, args.registry()?).await?,
+    };
+    for entry in entries {
+        println!("{}", entry);
+    }
 
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.filter_level(args.log_level);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    let logger = logger.build();
+    let max_level = logger.filter();
+    jobs::init_logger(Box::new(logger), max_level);
+
+    match &args.command {
+        Subcommand::Build(build_args) => build(&args, build_args),
+        Subcommand::BuildAll(build_all_args) => build_all(&args, build_all_args),
+        Subcommand::Push(push_args) => push(&args, push_args).await,
+        Subcommand::Pull(pull_args) => pull(&args, pull_args).await,
+        Subcommand::Inspect(inspect_args) => inspect(&args, inspect_args).await,
+        Subcommand::List(list_args) => list(&args, list_args).await,
+    }
+}
@@ -0,0 +1,462 @@
+use crate::grammar::Grammar;
+use anyhow::{bail, Context};
+use log::warn;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// Copies the `.scm` files below `src` to `dst`, keeping their relative paths
+fn copy_queries(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(src)? {
+        let path = entry?.path();
+        let destination = dst.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            copy_queries(&path, &destination)?;
+        } else if path.extension() == Some("scm".as_ref()) {
+            std::fs::create_dir_all(dst)?;
+            std::fs::copy(&path, &destination)
+                .with_context(|| format!("Failed to copy {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn has_files(dir: &Path) -> anyhow::Result<bool> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_dir() || has_files(&path)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Collects the query files of `grammar` into `dst`, returning whether there were any
+///
+/// An explicit `queries_dir` is used as is. Otherwise the `queries` directory of the grammar, or
+/// of the repository, is taken, with the query kinds the grammar declares replaced by the
+/// concatenation of their declared files.
+pub fn collect(grammar: &Grammar, dst: &Path) -> anyhow::Result<bool> {
+    if dst.exists() {
+        std::fs::remove_dir_all(dst)?;
+    }
+    std::fs::create_dir_all(dst)?;
+
+    if let Some(queries_dir) = &grammar.queries_dir {
+        if !queries_dir.is_dir() {
+            bail!("Query directory {} does not exist", queries_dir.display());
+        }
+        copy_queries(queries_dir, dst)?;
+        return has_files(dst);
+    }
+
+    let queries_dir = [grammar.path.join("queries"), grammar.root.join("queries")]
+        .into_iter()
+        .find(|dir| dir.is_dir());
+    if let Some(queries_dir) = queries_dir {
+        copy_queries(&queries_dir, dst)?;
+    }
+    // Declared queries may combine several files, e.g. TypeScript extending JavaScript highlights
+    for (kind, paths) in &grammar.metadata.queries {
+        let mut combined = String::new();
+        for path in paths {
+            let path = grammar.root.join(path);
+            match std::fs::read_to_string(&path) {
+                Ok(query) => {
+                    combined.push_str(&query);
+                    if !combined.ends_with('\n') {
+                        combined.push('\n');
+                    }
+                }
+                Err(err) => warn!("Skipping declared query {}: {}", path.display(), err),
+            }
+        }
+        if !combined.is_empty() {
+            std::fs::write(dst.join(format!("{}.scm", kind)), combined)?;
+        }
+    }
+    has_files(dst)
+}
+
+/// How problems found in query files are treated
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuerySeverity {
+    /// Fail the build
+    Deny,
+    /// Log a warning and publish anyway
+    Warn,
+    /// Do not validate queries
+    Allow,
+}
+
+impl std::fmt::Display for QuerySeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = clap::ValueEnum::to_possible_value(self).expect("no skipped variants");
+        f.write_str(value.get_name())
+    }
+}
+
+#[derive(Deserialize)]
+struct NodeType {
+    #[serde(rename = "type")]
+    kind: String,
+    named: bool,
+    #[serde(default)]
+    fields: BTreeMap<String, serde_json::Value>,
+}
+
+/// Node types and fields a parser produces, as listed in its `node-types.json`
+struct NodeTypes {
+    named: HashSet<String>,
+    anonymous: HashSet<String>,
+    fields: HashSet<String>,
+}
+
+impl NodeTypes {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let node_types: Vec<NodeType> = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let mut types = NodeTypes {
+            named: ["ERROR", "MISSING", "_"].map(str::to_string).into(),
+            anonymous: HashSet::new(),
+            fields: HashSet::new(),
+        };
+        for node_type in node_types {
+            types.fields.extend(node_type.fields.into_keys());
+            if node_type.named {
+                types.named.insert(node_type.kind);
+            } else {
+                types.anonymous.insert(node_type.kind);
+            }
+        }
+        Ok(types)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum Token {
+    Open,
+    Close,
+    OpenAlternation,
+    CloseAlternation,
+    Colon,
+    /// Node type, field name or predicate argument
+    Identifier(String),
+    String(String),
+    Capture(String),
+    /// `#eq?` and the like
+    Predicate(String),
+    /// `!field`
+    NegatedField(String),
+    /// Quantifiers and anchors, which validation does not care about
+    Operator,
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '/' | '?' | '!')
+}
+
+/// Splits a query into tokens, tracking 1-based line and column numbers
+struct Lexer<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer<'_> {
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn identifier(&mut self) -> String {
+        let mut identifier = String::new();
+        while let Some(c) = self.chars.next_if(|c| is_identifier_char(*c)) {
+            self.column += 1;
+            identifier.push(c);
+        }
+        identifier
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let mut string = String::new();
+        loop {
+            match self.next_char()? {
+                '"' => return Some(string),
+                '\\' => string.push(match self.next_char()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    '0' => '\0',
+                    c => c,
+                }),
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn tokenize(mut self) -> Result<Vec<(Token, usize, usize)>, String> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.next_char() {
+            let (line, column) = (self.line, self.column);
+            let token = match c {
+                ';' => {
+                    while self.chars.next_if(|c| *c != '\n').is_some() {}
+                    continue;
+                }
+                c if c.is_whitespace() => continue,
+                '(' => Token::Open,
+                ')' => Token::Close,
+                '[' => Token::OpenAlternation,
+                ']' => Token::CloseAlternation,
+                ':' => Token::Colon,
+                '*' | '+' | '?' | '.' => Token::Operator,
+                '@' => Token::Capture(self.identifier()),
+                '#' => Token::Predicate(self.identifier()),
+                '!' => Token::NegatedField(self.identifier()),
+                '"' => match self.string() {
+                    Some(string) => Token::String(string),
+                    None => return Err(format!("{}:{}: Unterminated string", line, column)),
+                },
+                c if is_identifier_char(c) => {
+                    Token::Identifier(format!("{}{}", c, self.identifier()))
+                }
+                c => return Err(format!("{}:{}: Unexpected character {:?}", line, column, c)),
+            };
+            tokens.push((token, line, column));
+        }
+        Ok(tokens)
+    }
+}
+
+/// Problems of one query file, each prefixed with its `line:column`
+///
+/// Node types, anonymous nodes and fields have to exist in `node_types`. Like tree-sitter, which
+/// resolves capture names while parsing, predicates may refer to any capture defined before them.
+fn check_query(query: &str, node_types: &NodeTypes) -> Vec<String> {
+    let lexer = Lexer {
+        chars: query.chars().peekable(),
+        line: 1,
+        column: 0,
+    };
+    let tokens = match lexer.tokenize() {
+        Ok(tokens) => tokens,
+        Err(problem) => return vec![problem],
+    };
+    let mut problems = Vec::new();
+    let mut defined = HashSet::new();
+    // Indices of open parentheses and brackets, noting which parentheses enclose a predicate
+    let mut stack: Vec<(usize, bool)> = Vec::new();
+
+    for (index, (token, line, column)) in tokens.iter().enumerate() {
+        let previous = index.checked_sub(1).map(|index| &tokens[index].0);
+        let following = tokens.get(index + 1).map(|(token, _, _)| token);
+        let in_predicate = stack.iter().any(|(_, predicate)| *predicate);
+        let problem = match token {
+            Token::Open => {
+                stack.push((index, matches!(following, Some(Token::Predicate(_)))));
+                None
+            }
+            Token::OpenAlternation => {
+                stack.push((index, false));
+                None
+            }
+            Token::Close | Token::CloseAlternation => {
+                let expected = match token {
+                    Token::Close => Token::Open,
+                    _ => Token::OpenAlternation,
+                };
+                match stack.pop() {
+                    Some((open, _)) if tokens[open].0 == expected => None,
+                    _ => {
+                        problems.push(format!("{}:{}: Unbalanced parentheses", line, column));
+                        return problems;
+                    }
+                }
+            }
+            Token::Capture(name) if in_predicate => (!defined.contains(name))
+                .then(|| format!("Predicate refers to undefined capture @{}", name)),
+            Token::Capture(name) => {
+                defined.insert(name.clone());
+                name.is_empty()
+                    .then(|| "Capture without a name".to_string())
+            }
+            _ if in_predicate => None,
+            Token::Identifier(name) | Token::NegatedField(name)
+                if following == Some(&Token::Colon) || matches!(token, Token::NegatedField(_)) =>
+            {
+                (!node_types.fields.contains(name)).then(|| format!("Unknown field {}", name))
+            }
+            // `supertype/subtype` matches a subtype through its supertype
+            Token::Identifier(name) if previous == Some(&Token::Open) => name
+                .split('/')
+                .find(|node_type| !node_types.named.contains(*node_type))
+                .map(|node_type| format!("Unknown node type {}", node_type)),
+            Token::String(value) => (!node_types.anonymous.contains(value))
+                .then(|| format!("Unknown anonymous node {:?}", value)),
+            _ => None,
+        };
+        if let Some(problem) = problem {
+            problems.push(format!("{}:{}: {}", line, column, problem));
+        }
+    }
+    if let Some(&(open, _)) = stack.last() {
+        let (_, line, column) = &tokens[open];
+        problems.push(format!("{}:{}: Unbalanced parentheses", line, column));
+    }
+    problems
+}
+
+fn query_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            query_files(&path, files)?;
+        } else if path.extension() == Some("scm".as_ref()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Checks the queries in `queries_dir` against the `node-types.json` of the parser in `src_dir`
+///
+/// Problems fail the check with [`QuerySeverity::Deny`] and are only logged otherwise.
+pub fn validate(queries_dir: &Path, src_dir: &Path, severity: QuerySeverity) -> anyhow::Result<()> {
+    if severity == QuerySeverity::Allow {
+        return Ok(());
+    }
+    let node_types_path = src_dir.join("node-types.json");
+    if !node_types_path.is_file() {
+        warn!(
+            "{} does not exist, skipping query validation",
+            node_types_path.display()
+        );
+        return Ok(());
+    }
+    let node_types = NodeTypes::load(&node_types_path)?;
+
+    let mut files = Vec::new();
+    query_files(queries_dir, &mut files)?;
+    files.sort();
+    let mut invalid = 0;
+    for file in &files {
+        let query = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {}", file.display()))?;
+        let relative = file.strip_prefix(queries_dir).unwrap_or(file);
+        let problems = check_query(&query, &node_types);
+        for problem in &problems {
+            warn!("{}:{}", relative.display(), problem);
+        }
+        invalid += usize::from(!problems.is_empty());
+    }
+    if invalid > 0 && severity == QuerySeverity::Deny {
+        bail!(
+            "{} of {} query files do not match the parser, pass --invalid-queries warn to publish anyway",
+            invalid,
+            files.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_types() -> NodeTypes {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        NodeTypes {
+            named: names(&[
+                "ERROR",
+                "MISSING",
+                "_",
+                "expression",
+                "call",
+                "identifier",
+                "string",
+            ]),
+            anonymous: names(&["(", ")", "\"", "\\", "+"]),
+            fields: names(&["function", "arguments", "name"]),
+        }
+    }
+
+    fn check(query: &str) -> Vec<String> {
+        check_query(query, &node_types())
+    }
+
+    #[test]
+    fn accepts_valid_queries() {
+        let query = r#"
+            ; Fields and negated fields
+            (call function: (identifier) @function !arguments)
+            (expression/call name: (_) @name)
+            ["(" ")" "\"" "\\"] @punctuation
+            (call
+              .
+              (identifier)+ @first
+              (string)? @string
+              [(identifier) (expression)]* @rest .)
+            ((identifier) @constant (#match? @constant "^[A-Z]"))
+            (MISSING identifier) @missing
+            (ERROR) @error
+        "#;
+        assert_eq!(check(query), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_unknown_names() {
+        let query = r#"(call functio: (identifer) !argument)
+(expression/cal) "-" "\n""#;
+        assert_eq!(
+            check(query),
+            [
+                "1:7: Unknown field functio",
+                "1:17: Unknown node type identifer",
+                "1:28: Unknown field argument",
+                "2:2: Unknown node type cal",
+                "2:18: Unknown anonymous node \"-\"",
+                "2:22: Unknown anonymous node \"\\n\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_predicate_captures_like_tree_sitter() {
+        // Captures are shared by the whole query, a top-level predicate is a pattern of its own
+        assert_eq!(
+            check(r#"(identifier) @z (#eq? @z "x")"#),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            check(r#"((identifier) @a (#eq? @b "x"))"#),
+            ["1:24: Predicate refers to undefined capture @b"]
+        );
+        assert_eq!(
+            check(r#"((#eq? @a "x") (identifier) @a)"#),
+            ["1:8: Predicate refers to undefined capture @a"]
+        );
+    }
+
+    #[test]
+    fn reports_unbalanced_parentheses() {
+        assert_eq!(check("(call (identifier)"), ["1:1: Unbalanced parentheses"]);
+        assert_eq!(check("(call))"), ["1:7: Unbalanced parentheses"]);
+        assert_eq!(
+            check("[(call)) (identifier)"),
+            ["1:8: Unbalanced parentheses"]
+        );
+        assert_eq!(check("(call \"("), ["1:7: Unterminated string"]);
+    }
+}
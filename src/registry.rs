@@ -0,0 +1,946 @@
+use crate::{
+    build::{ArtifactKind, BuildRecord},
+    grammar::GrammarMetadata,
+    target,
+};
+use anyhow::{bail, Context};
+use log::info;
+use oci_distribution::{
+    annotations,
+    client::{ClientConfig, ClientProtocol, Config, ImageLayer},
+    manifest::{
+        ImageIndexEntry, OciImageIndex, OciImageManifest, OciManifest,
+        IMAGE_MANIFEST_LIST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+    },
+    secrets::RegistryAuth,
+    Client, Reference,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+pub const CONFIG_MEDIA_TYPE: &str = "application/vnd.tree-sitter.parser.config.v1+json";
+pub const C_DYNLIB_MEDIA_TYPE: &str = "application/vnd.tree-sitter.parser.c-dynlib.v1";
+pub const C_STATIC_MEDIA_TYPE: &str = "application/vnd.tree-sitter.parser.c-static.v1";
+pub const WASM_MEDIA_TYPE: &str = "application/vnd.tree-sitter.parser.wasm.v1+wasm";
+pub const SOURCES_MEDIA_TYPE: &str = "application/vnd.tree-sitter.parser.src.v1.tar+gzip";
+
+pub const QUERIES_MEDIA_TYPE: &str = "application/vnd.tree-sitter.parser.queries.v1.tar+gzip";
+
+/// Layer annotation holding the Rust target triple a library was compiled for
+pub const TARGET_ANNOTATION: &str = "org.tree-sitter.parser.target";
+pub const ABI_ANNOTATION: &str = "org.tree-sitter.parser.abi";
+
+/// Grammar metadata stored in the config blob of a published parser
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GrammarConfig {
+    pub name: String,
+    pub version: String,
+    pub target: String,
+    #[serde(flatten)]
+    pub metadata: GrammarMetadata,
+}
+
+/// Parser ABI versions a tree-sitter runtime can load
+#[derive(clap::Args, Clone, Copy, Default)]
+pub struct AbiRange {
+    /// Lowest accepted parser ABI, `TREE_SITTER_MIN_COMPATIBLE_LANGUAGE_VERSION` of the runtime
+    #[clap(long, env = "TS_MIN_ABI")]
+    pub min_abi: Option<u32>,
+    /// Highest accepted parser ABI, `TREE_SITTER_LANGUAGE_VERSION` of the runtime
+    #[clap(long, env = "TS_MAX_ABI")]
+    pub max_abi: Option<u32>,
+}
+
+impl AbiRange {
+    pub fn is_bounded(&self) -> bool {
+        self.min_abi.is_some() || self.max_abi.is_some()
+    }
+
+    /// Whether `abi` is in the range, an unknown ABI only being accepted by an unbounded range
+    pub fn contains(&self, abi: Option<u32>) -> bool {
+        match abi {
+            Some(abi) => {
+                self.min_abi.is_none_or(|min| abi >= min)
+                    && self.max_abi.is_none_or(|max| abi <= max)
+            }
+            None => !self.is_bounded(),
+        }
+    }
+}
+
+impl std::fmt::Display for AbiRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(min) = self.min_abi {
+            write!(f, "{}", min)?;
+        }
+        f.write_str("..")?;
+        if let Some(max) = self.max_abi {
+            write!(f, "={}", max)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::Args)]
+pub struct RegistryArgs {
+    /// Use plain HTTP to talk to the registry (e.g. for a local `registry:2`)
+    #[clap(long, global = true)]
+    pub insecure: bool,
+    /// Registry user name
+    #[clap(long, global = true, env = "TS_REGISTRY_USERNAME")]
+    pub username: Option<String>,
+    /// Registry password
+    #[clap(
+        long,
+        global = true,
+        env = "TS_REGISTRY_PASSWORD",
+        hide_env_values = true
+    )]
+    pub password: Option<String>,
+}
+
+impl RegistryArgs {
+    pub fn client(&self) -> Client {
+        Client::new(ClientConfig {
+            protocol: if self.insecure {
+                ClientProtocol::Http
+            } else {
+                ClientProtocol::Https
+            },
+            ..Default::default()
+        })
+    }
+
+    fn scheme(&self) -> &'static str {
+        if self.insecure {
+            "http"
+        } else {
+            "https"
+        }
+    }
+
+    pub fn auth(&self) -> RegistryAuth {
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => {
+                RegistryAuth::Basic(username.clone(), password.clone())
+            }
+            _ => RegistryAuth::Anonymous,
+        }
+    }
+}
+
+fn media_type(kind: ArtifactKind) -> &'static str {
+    match kind {
+        ArtifactKind::CDynlib => C_DYNLIB_MEDIA_TYPE,
+        ArtifactKind::CStatic => C_STATIC_MEDIA_TYPE,
+        ArtifactKind::Wasm => WASM_MEDIA_TYPE,
+    }
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+fn file_layer(path: &Path, media_type: &str, target: Option<&str>) -> anyhow::Result<ImageLayer> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let title = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut layer_annotations = HashMap::from([(
+        annotations::ORG_OPENCONTAINERS_IMAGE_TITLE.to_string(),
+        title,
+    )]);
+    if let Some(target) = target {
+        layer_annotations.insert(TARGET_ANNOTATION.to_string(), target.to_string());
+    }
+    Ok(ImageLayer::new(
+        data,
+        media_type.to_string(),
+        Some(layer_annotations),
+    ))
+}
+
+fn directory_layer(dir: &Path, title: &str, media_type: &str) -> anyhow::Result<ImageLayer> {
+    let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut archive = tar::Builder::new(encoder);
+    archive.mode(tar::HeaderMode::Deterministic);
+    archive
+        .append_dir_all(title, dir)
+        .with_context(|| format!("Failed to archive {}", dir.display()))?;
+    let data = archive.into_inner()?.finish()?;
+    Ok(ImageLayer::new(
+        data,
+        media_type.to_string(),
+        Some(HashMap::from([(
+            annotations::ORG_OPENCONTAINERS_IMAGE_TITLE.to_string(),
+            title.to_string(),
+        )])),
+    ))
+}
+
+fn manifest_digest(manifest: &OciImageManifest) -> anyhow::Result<(String, i64)> {
+    // The client uploads manifests in canonical JSON, so the digest is computed over the same form
+    let mut data = Vec::new();
+    let mut serializer =
+        serde_json::Serializer::with_formatter(&mut data, olpc_cjson::CanonicalFormatter::new());
+    manifest.serialize(&mut serializer)?;
+    Ok((sha256_digest(&data), data.len() as i64))
+}
+
+fn annotation<'a>(entry: &'a ImageIndexEntry, key: &str) -> Option<&'a String> {
+    entry.annotations.as_ref()?.get(key)
+}
+
+/// ABI version of the parser behind an index entry, defaulting to that of the whole index for
+/// indexes pushed before entries were annotated
+fn entry_abi(entry: &ImageIndexEntry, index: &OciImageIndex) -> Option<u32> {
+    annotation(entry, ABI_ANNOTATION)
+        .or_else(|| index.annotations.as_ref()?.get(ABI_ANNOTATION))
+        .and_then(|abi| abi.parse().ok())
+}
+
+/// Adds the entries of `existing` to `entries` unless one of them has the same target and ABI
+///
+/// Kept entries are annotated with their ABI, which they may only have inherited from the index.
+fn merge_entries(entries: &mut Vec<ImageIndexEntry>, existing: &OciImageIndex) {
+    for mut entry in existing.manifests.clone() {
+        let abi = entry_abi(&entry, existing);
+        let replaced = entries.iter().any(|pushed| {
+            annotation(pushed, TARGET_ANNOTATION) == annotation(&entry, TARGET_ANNOTATION)
+                && annotation(pushed, ABI_ANNOTATION).and_then(|abi| abi.parse().ok()) == abi
+        });
+        if replaced {
+            continue;
+        }
+        if let Some(abi) = abi {
+            entry
+                .annotations
+                .get_or_insert_with(HashMap::new)
+                .insert(ABI_ANNOTATION.to_string(), abi.to_string());
+        }
+        entries.push(entry);
+    }
+}
+
+/// Whether all `entries` have the same ABI, which the index then states as well
+fn shares_abi(entries: &[ImageIndexEntry]) -> bool {
+    let mut abis = entries
+        .iter()
+        .map(|entry| annotation(entry, ABI_ANNOTATION));
+    let first = abis.next();
+    abis.all(|abi| Some(abi) == first)
+}
+
+/// Uploads one image manifest per built target and an image index referencing them
+///
+/// With `merge`, the entries of an existing index at `reference` are kept unless this build
+/// replaces their target and ABI, so that one tag can offer parsers for several ABIs.
+pub async fn push(
+    registry: &RegistryArgs,
+    reference: &Reference,
+    version: &str,
+    record: &BuildRecord,
+    merge: bool,
+) -> anyhow::Result<String> {
+    let mut client = registry.client();
+    let auth = registry.auth();
+    let sources = directory_layer(&record.sources, "src", SOURCES_MEDIA_TYPE)?;
+    let queries = record
+        .queries
+        .as_deref()
+        .map(|queries| directory_layer(queries, "queries", QUERIES_MEDIA_TYPE))
+        .transpose()?;
+    let wasm = record
+        .wasm
+        .as_deref()
+        .map(|wasm| file_layer(wasm, WASM_MEDIA_TYPE, None))
+        .transpose()?;
+    let mut annotations = HashMap::from([
+        (
+            annotations::ORG_OPENCONTAINERS_IMAGE_TITLE.to_string(),
+            record.name.clone(),
+        ),
+        (
+            annotations::ORG_OPENCONTAINERS_IMAGE_VERSION.to_string(),
+            version.to_string(),
+        ),
+    ]);
+    if let Some(language_version) = record.language_version {
+        annotations.insert(ABI_ANNOTATION.to_string(), language_version.to_string());
+    }
+    if let Some(git) = &record.git {
+        annotations.insert(
+            annotations::ORG_OPENCONTAINERS_IMAGE_SOURCE.to_string(),
+            git.url.clone(),
+        );
+        annotations.insert(
+            annotations::ORG_OPENCONTAINERS_IMAGE_REVISION.to_string(),
+            git.commit.clone(),
+        );
+    }
+
+    // Only a missing tag means there is nothing to merge, other errors would lose its parsers
+    let existing = if merge && manifest_exists(registry, reference).await? {
+        match pull_index(&mut client, registry, reference).await? {
+            Some(existing) => Some(existing),
+            None => bail!(
+                "{} is a single image manifest, not an index to merge into",
+                reference.whole()
+            ),
+        }
+    } else {
+        if merge {
+            info!("{} does not exist yet, nothing to merge", reference.whole());
+        }
+        None
+    };
+
+    let mut entries = Vec::new();
+    for target in &record.targets {
+        let grammar = GrammarConfig {
+            name: record.name.clone(),
+            version: version.to_string(),
+            target: target.target.clone(),
+            metadata: record.metadata.clone(),
+        };
+        let mut layers = Vec::new();
+        let libraries = [
+            (&target.library, C_DYNLIB_MEDIA_TYPE),
+            (&target.static_library, C_STATIC_MEDIA_TYPE),
+        ];
+        for (library, media_type) in libraries {
+            if let Some(library) = library {
+                layers.push(file_layer(library, media_type, Some(&target.target))?);
+            }
+        }
+        layers.extend(wasm.clone());
+        layers.extend(queries.clone());
+        layers.push(sources.clone());
+        let config = Config::new(
+            serde_json::to_vec(&grammar)?,
+            CONFIG_MEDIA_TYPE.to_string(),
+            None,
+        );
+        let mut manifest = OciImageManifest::build(&layers, &config, Some(annotations.clone()));
+        manifest.media_type = Some(OCI_IMAGE_MEDIA_TYPE.to_string());
+        let (digest, size) = manifest_digest(&manifest)?;
+
+        info!("Pushing {} for {}", digest, target.target);
+        let platform_reference = Reference::with_digest(
+            reference.registry().to_string(),
+            reference.repository().to_string(),
+            digest.clone(),
+        );
+        client
+            .push(&platform_reference, &layers, config, &auth, Some(manifest))
+            .await
+            .with_context(|| format!("Failed to push {}", platform_reference.whole()))?;
+
+        entries.push(ImageIndexEntry {
+            media_type: OCI_IMAGE_MEDIA_TYPE.to_string(),
+            digest,
+            size,
+            platform: Some(target::platform(&target.target)?),
+            annotations: Some(
+                [
+                    Some((TARGET_ANNOTATION.to_string(), target.target.clone())),
+                    record
+                        .language_version
+                        .map(|abi| (ABI_ANNOTATION.to_string(), abi.to_string())),
+                ]
+                .into_iter()
+                .flatten()
+                .collect(),
+            ),
+        });
+    }
+
+    if let Some(existing) = &existing {
+        merge_entries(&mut entries, existing);
+    }
+    if !shares_abi(&entries) {
+        annotations.remove(ABI_ANNOTATION);
+    }
+
+    let index = OciImageIndex {
+        schema_version: 2,
+        media_type: Some(IMAGE_MANIFEST_LIST_MEDIA_TYPE.to_string()),
+        manifests: entries,
+        annotations: Some(annotations),
+    };
+    info!("Pushing {}", reference.whole());
+    client
+        .push_manifest_list(reference, &auth, index)
+        .await
+        .with_context(|| format!("Failed to push {}", reference.whole()))
+}
+
+/// Returns the image index behind `reference`, or `None` for a single image manifest
+async fn pull_index(
+    client: &mut Client,
+    registry: &RegistryArgs,
+    reference: &Reference,
+) -> anyhow::Result<Option<OciImageIndex>> {
+    let (manifest, _digest) = client
+        .pull_manifest(reference, &registry.auth())
+        .await
+        .with_context(|| format!("Failed to fetch manifest of {}", reference.whole()))?;
+    Ok(match manifest {
+        OciManifest::Image(_) => None,
+        OciManifest::ImageIndex(index) => Some(index),
+    })
+}
+
+fn with_digest(reference: &Reference, digest: &str) -> Reference {
+    Reference::with_digest(
+        reference.registry().to_string(),
+        reference.repository().to_string(),
+        digest.to_string(),
+    )
+}
+
+/// Resolves `reference` to the image manifests built for `target` with an ABI in `abi`, highest
+/// ABI first
+///
+/// Target independent artifacts like wasm modules and queries can come from any target, with
+/// `any_target` the manifests of other targets are included, after those of `target` of the same
+/// ABI.
+async fn resolve_target(
+    client: &mut Client,
+    registry: &RegistryArgs,
+    reference: &Reference,
+    target: &str,
+    any_target: bool,
+    abi: &AbiRange,
+) -> anyhow::Result<Vec<Reference>> {
+    let Some(index) = pull_index(client, registry, reference).await? else {
+        return Ok(vec![reference.clone()]);
+    };
+    // Platforms cannot tell targets like musl and glibc apart, only the target annotation can
+    let is_target = |entry: &ImageIndexEntry| {
+        annotation(entry, TARGET_ANNOTATION).map(String::as_str) == Some(target)
+    };
+    let candidates: Vec<&ImageIndexEntry> = index
+        .manifests
+        .iter()
+        .filter(|entry| any_target || is_target(entry))
+        .collect();
+    if candidates.is_empty() {
+        bail!("{} has no parser for target {}", reference.whole(), target);
+    }
+    let mut compatible: Vec<&ImageIndexEntry> = candidates
+        .iter()
+        .copied()
+        .filter(|entry| abi.contains(entry_abi(entry, &index)))
+        .collect();
+    compatible.sort_by_key(|entry| {
+        (
+            std::cmp::Reverse(entry_abi(entry, &index)),
+            !is_target(entry),
+        )
+    });
+    if compatible.is_empty() {
+        let available: Vec<String> = candidates
+            .iter()
+            .map(|entry| {
+                entry_abi(entry, &index).map_or("unknown".to_string(), |abi| abi.to_string())
+            })
+            .collect();
+        bail!(
+            "{} has no parser for target {} with an ABI in {}, only ABI {}",
+            reference.whole(),
+            target,
+            abi,
+            available.join(", ")
+        );
+    }
+    Ok(compatible
+        .into_iter()
+        .map(|entry| with_digest(reference, &entry.digest))
+        .collect())
+}
+
+async fn pull_manifest_and_config(
+    client: &mut Client,
+    registry: &RegistryArgs,
+    reference: &Reference,
+) -> anyhow::Result<(OciImageManifest, GrammarConfig)> {
+    let (manifest, digest, config) = client
+        .pull_manifest_and_config(reference, &registry.auth())
+        .await
+        .with_context(|| format!("Failed to fetch manifest of {}", reference.whole()))?;
+    if let Some(requested) = reference.digest() {
+        if digest != requested {
+            bail!(
+                "Digest mismatch for manifest of {}: registry returned {}",
+                reference.whole(),
+                digest
+            );
+        }
+    }
+    if manifest.config.media_type != CONFIG_MEDIA_TYPE {
+        bail!(
+            "{} is not a parser artifact (config media type {})",
+            reference.whole(),
+            manifest.config.media_type
+        );
+    }
+    if sha256_digest(config.as_bytes()) != manifest.config.digest {
+        bail!("Digest mismatch for config of {}", reference.whole());
+    }
+    let grammar = serde_json::from_str(&config)?;
+    Ok((manifest, grammar))
+}
+
+/// Fetches the manifests and grammar metadata of every platform of a published parser
+pub async fn inspect(
+    registry: &RegistryArgs,
+    reference: &Reference,
+) -> anyhow::Result<Vec<(OciImageManifest, GrammarConfig)>> {
+    let mut client = registry.client();
+    let references = match pull_index(&mut client, registry, reference).await? {
+        Some(index) => index
+            .manifests
+            .iter()
+            .map(|entry| with_digest(reference, &entry.digest))
+            .collect(),
+        None => vec![reference.clone()],
+    };
+    let mut platforms = Vec::new();
+    for reference in references {
+        platforms.push(pull_manifest_and_config(&mut client, registry, &reference).await?);
+    }
+    Ok(platforms)
+}
+
+/// Downloads the blob `digest` of `reference`, verifying its contents
+async fn pull_layer(
+    client: &mut Client,
+    reference: &Reference,
+    digest: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    client
+        .pull_blob(reference, digest, &mut data)
+        .await
+        .with_context(|| format!("Failed to download layer {}", digest))?;
+    let actual = sha256_digest(&data);
+    if actual != digest {
+        bail!(
+            "Digest mismatch for layer {}: downloaded data has digest {}",
+            digest,
+            actual
+        );
+    }
+    Ok(data)
+}
+
+/// Downloads the artifact of `kind` built for `target` with an ABI in `abi` and installs it into
+/// `parser_dir`
+pub async fn pull(
+    registry: &RegistryArgs,
+    reference: &Reference,
+    target: &str,
+    kind: ArtifactKind,
+    abi: &AbiRange,
+    parser_dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let mut client = registry.client();
+    // Wasm modules are the same in the manifest of every target
+    let any_target = kind == ArtifactKind::Wasm;
+    let candidates =
+        resolve_target(&mut client, registry, reference, target, any_target, abi).await?;
+    for reference in &candidates {
+        let (manifest, grammar) =
+            pull_manifest_and_config(&mut client, registry, reference).await?;
+        let layer = manifest
+            .layers
+            .iter()
+            .filter(|layer| layer.media_type == media_type(kind))
+            .find(|layer| {
+                let layer_target = layer
+                    .annotations
+                    .as_ref()
+                    .and_then(|annotations| annotations.get(TARGET_ANNOTATION));
+                // Target independent layers such as wasm modules carry no target annotation
+                any_target || layer_target.unwrap_or(&grammar.target) == target
+            });
+        let Some(layer) = layer else {
+            continue;
+        };
+        let data = pull_layer(&mut client, reference, &layer.digest).await?;
+
+        std::fs::create_dir_all(parser_dir)?;
+        let library_path = parser_dir.join(kind.file_name(&grammar.name, &grammar.target));
+        std::fs::write(&library_path, data)
+            .with_context(|| format!("Failed to write {}", library_path.display()))?;
+        return Ok(library_path);
+    }
+    bail!(
+        "{} contains no {} artifact for target {}",
+        reference.whole(),
+        kind,
+        target
+    )
+}
+
+/// Downloads the query files published with the parser for `target` into `queries_dir/<grammar>`
+pub async fn pull_queries(
+    registry: &RegistryArgs,
+    reference: &Reference,
+    target: &str,
+    abi: &AbiRange,
+    queries_dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let mut client = registry.client();
+    // Queries are the same in the manifest of every target
+    let candidates = resolve_target(&mut client, registry, reference, target, true, abi).await?;
+    let mut found = None;
+    for reference in &candidates {
+        let (manifest, grammar) =
+            pull_manifest_and_config(&mut client, registry, reference).await?;
+        if let Some(layer) = manifest
+            .layers
+            .iter()
+            .find(|layer| layer.media_type == QUERIES_MEDIA_TYPE)
+        {
+            found = Some((
+                pull_layer(&mut client, reference, &layer.digest).await?,
+                grammar,
+            ));
+            break;
+        }
+    }
+    let (data, grammar) =
+        found.with_context(|| format!("{} contains no query files", reference.whole()))?;
+
+    let grammar_dir = queries_dir.join(&grammar.name);
+    if grammar_dir.exists() {
+        std::fs::remove_dir_all(&grammar_dir)?;
+    }
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(data.as_slice()));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        // Entries are stored below `queries/`, never outside of it
+        let path = entry.path()?.into_owned();
+        let relative = path.strip_prefix("queries").unwrap_or(&path);
+        if !relative
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+        {
+            bail!("Invalid path {} in query layer", path.display());
+        }
+        let destination = grammar_dir.join(relative);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        entry
+            .unpack(&destination)
+            .with_context(|| format!("Failed to write {}", destination.display()))?;
+    }
+    Ok(grammar_dir)
+}
+
+/// Splits a registry prefix like `registry.local/parsers` into host and repository prefix
+fn split_registry(registry: &str) -> (&str, &str) {
+    registry.split_once('/').unwrap_or((registry, ""))
+}
+
+/// Parameters of a `WWW-Authenticate: Bearer realm="…",service="…",scope="…"` challenge
+fn bearer_challenge(header: &str) -> Option<Vec<(String, String)>> {
+    let mut rest = header.trim().strip_prefix("Bearer ")?.trim_start();
+    let mut parameters = Vec::new();
+    while let Some((name, value)) = rest.split_once('=') {
+        // Quoted values like scopes may contain commas
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"')?,
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        parameters.push((name.trim().to_string(), value.to_string()));
+        rest = remainder.trim_start_matches([',', ' ']);
+    }
+    Some(parameters)
+}
+
+/// Sends a GET request to the registry API, authenticating like the registry demands
+///
+/// Registries like `registry:2` take Basic credentials directly, token based ones like GHCR or
+/// Docker Hub answer with a Bearer challenge, whose token is fetched from the realm it names.
+async fn registry_get(
+    registry: &RegistryArgs,
+    url: &str,
+    accept: &str,
+) -> anyhow::Result<reqwest::Response> {
+    let client = reqwest::Client::new();
+    let basic_auth = |request: reqwest::RequestBuilder| match registry.auth() {
+        RegistryAuth::Basic(username, password) => request.basic_auth(username, Some(password)),
+        RegistryAuth::Anonymous => request,
+    };
+    let response = basic_auth(client.get(url).header(reqwest::header::ACCEPT, accept))
+        .send()
+        .await
+        .with_context(|| format!("Failed to query {}", url))?;
+    if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(response);
+    }
+    let Some(challenge) = response
+        .headers()
+        .get(reqwest::header::WWW_AUTHENTICATE)
+        .and_then(|header| header.to_str().ok())
+        .and_then(bearer_challenge)
+    else {
+        return Ok(response);
+    };
+    let Some((_, realm)) = challenge.iter().find(|(name, _)| name == "realm") else {
+        return Ok(response);
+    };
+    let query: Vec<&(String, String)> = challenge
+        .iter()
+        .filter(|(name, _)| name != "realm")
+        .collect();
+    let token: serde_json::Value = basic_auth(client.get(realm).query(&query))
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("Failed to fetch a token from {}", realm))?
+        .json()
+        .await?;
+    let token = token["token"]
+        .as_str()
+        .or(token["access_token"].as_str())
+        .with_context(|| format!("{} returned no token", realm))?;
+    client
+        .get(url)
+        .header(reqwest::header::ACCEPT, accept)
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("Failed to query {}", url))
+}
+
+async fn get_json(registry: &RegistryArgs, url: &str) -> anyhow::Result<serde_json::Value> {
+    let response = registry_get(registry, url, "application/json").await?;
+    if !response.status().is_success() {
+        bail!("Failed to query {}: {}", url, response.status());
+    }
+    Ok(response.json().await?)
+}
+
+/// Whether the registry has a manifest at `reference`, failing on anything but a clear answer
+async fn manifest_exists(registry: &RegistryArgs, reference: &Reference) -> anyhow::Result<bool> {
+    let url = format!(
+        "{}://{}/v2/{}/manifests/{}",
+        registry.scheme(),
+        reference.resolve_registry(),
+        reference.repository(),
+        reference.digest().or(reference.tag()).unwrap_or("latest")
+    );
+    let accept = [
+        OCI_IMAGE_INDEX_MEDIA_TYPE,
+        OCI_IMAGE_MEDIA_TYPE,
+        IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+    ]
+    .join(", ");
+    let response = registry_get(registry, &url, &accept).await?;
+    match response.status() {
+        status if status.is_success() => Ok(true),
+        reqwest::StatusCode::NOT_FOUND => Ok(false),
+        status => bail!("Failed to query {}: {}", url, status),
+    }
+}
+
+fn string_list(value: &serde_json::Value, key: &str) -> Vec<String> {
+    value[key]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.as_str().map(str::to_string))
+        .collect()
+}
+
+/// Lists the grammars published below the registry prefix
+pub async fn list_grammars(registry: &RegistryArgs, prefix: &str) -> anyhow::Result<Vec<String>> {
+    let (host, repository_prefix) = split_registry(prefix);
+    let catalog = get_json(
+        registry,
+        &format!("{}://{}/v2/_catalog", registry.scheme(), host),
+    )
+    .await?;
+    let repository_prefix = if repository_prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", repository_prefix)
+    };
+    Ok(string_list(&catalog, "repositories")
+        .into_iter()
+        .filter_map(|repository| {
+            repository
+                .strip_prefix(&repository_prefix)
+                .map(str::to_string)
+        })
+        .collect())
+}
+
+/// Lists the published versions of a grammar
+pub async fn list_tags(
+    registry: &RegistryArgs,
+    prefix: &str,
+    grammar_name: &str,
+) -> anyhow::Result<Vec<String>> {
+    let (host, repository_prefix) = split_registry(prefix);
+    let repository = if repository_prefix.is_empty() {
+        grammar_name.to_string()
+    } else {
+        format!("{}/{}", repository_prefix, grammar_name)
+    };
+    let tags = get_json(
+        registry,
+        &format!(
+            "{}://{}/v2/{}/tags/list",
+            registry.scheme(),
+            host,
+            repository
+        ),
+    )
+    .await?;
+    Ok(string_list(&tags, "tags"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(digest: &str, target: &str, abi: Option<u32>) -> ImageIndexEntry {
+        let mut annotations = HashMap::from([(TARGET_ANNOTATION.to_string(), target.to_string())]);
+        if let Some(abi) = abi {
+            annotations.insert(ABI_ANNOTATION.to_string(), abi.to_string());
+        }
+        ImageIndexEntry {
+            media_type: OCI_IMAGE_MEDIA_TYPE.to_string(),
+            digest: digest.to_string(),
+            size: 0,
+            platform: None,
+            annotations: Some(annotations),
+        }
+    }
+
+    fn index(manifests: Vec<ImageIndexEntry>, abi: Option<u32>) -> OciImageIndex {
+        OciImageIndex {
+            schema_version: 2,
+            media_type: Some(IMAGE_MANIFEST_LIST_MEDIA_TYPE.to_string()),
+            manifests,
+            annotations: abi
+                .map(|abi| HashMap::from([(ABI_ANNOTATION.to_string(), abi.to_string())])),
+        }
+    }
+
+    fn digests(entries: &[ImageIndexEntry]) -> Vec<(&str, Option<&str>)> {
+        entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.digest.as_str(),
+                    annotation(entry, ABI_ANNOTATION).map(String::as_str),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn abi_range() {
+        let unbounded = AbiRange::default();
+        assert!(unbounded.contains(Some(14)) && unbounded.contains(None));
+        assert_eq!(unbounded.to_string(), "..");
+
+        let range = AbiRange {
+            min_abi: Some(13),
+            max_abi: Some(14),
+        };
+        assert!(!range.contains(Some(12)));
+        assert!(range.contains(Some(13)) && range.contains(Some(14)));
+        assert!(!range.contains(Some(15)));
+        assert!(!range.contains(None));
+        assert_eq!(range.to_string(), "13..=14");
+
+        let min = AbiRange {
+            min_abi: Some(14),
+            max_abi: None,
+        };
+        assert!(min.contains(Some(15)) && !min.contains(Some(13)));
+        assert_eq!(min.to_string(), "14..");
+        let max = AbiRange {
+            min_abi: None,
+            max_abi: Some(14),
+        };
+        assert!(max.contains(Some(13)) && !max.contains(Some(15)));
+        assert_eq!(max.to_string(), "..=14");
+    }
+
+    #[test]
+    fn merge_replaces_same_target_and_abi() {
+        let linux = "x86_64-unknown-linux-gnu";
+        let darwin = "aarch64-apple-darwin";
+        let existing = index(
+            vec![
+                entry("old-linux-14", linux, Some(14)),
+                entry("old-linux-15", linux, Some(15)),
+                entry("old-darwin-14", darwin, Some(14)),
+            ],
+            None,
+        );
+        let mut entries = vec![entry("new-linux-15", linux, Some(15))];
+        merge_entries(&mut entries, &existing);
+        assert_eq!(
+            digests(&entries),
+            [
+                ("new-linux-15", Some("15")),
+                ("old-linux-14", Some("14")),
+                ("old-darwin-14", Some("14")),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_annotates_entries_with_the_abi_of_their_index() {
+        let linux = "x86_64-unknown-linux-gnu";
+        let existing = index(vec![entry("old-linux", linux, None)], Some(14));
+        let mut entries = vec![entry("new-linux", linux, Some(15))];
+        merge_entries(&mut entries, &existing);
+        assert_eq!(
+            digests(&entries),
+            [("new-linux", Some("15")), ("old-linux", Some("14"))]
+        );
+
+        // The replaced entry only had the ABI of its index
+        let mut entries = vec![entry("new-linux", linux, Some(14))];
+        merge_entries(&mut entries, &existing);
+        assert_eq!(digests(&entries), [("new-linux", Some("14"))]);
+    }
+
+    #[test]
+    fn index_abi_requires_a_shared_abi() {
+        let linux = "x86_64-unknown-linux-gnu";
+        let darwin = "aarch64-apple-darwin";
+        assert!(shares_abi(&[]));
+        assert!(shares_abi(&[
+            entry("a", linux, Some(14)),
+            entry("b", darwin, Some(14)),
+        ]));
+        assert!(!shares_abi(&[
+            entry("a", linux, Some(14)),
+            entry("b", linux, Some(15)),
+        ]));
+        assert!(!shares_abi(&[
+            entry("a", linux, Some(14)),
+            entry("b", darwin, None),
+        ]));
+    }
+}
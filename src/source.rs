@@ -0,0 +1,97 @@
+use anyhow::{bail, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{path::Path, process::Command};
+
+/// Repository and resolved commit a grammar was built from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GitSource {
+    pub url: String,
+    pub commit: String,
+}
+
+fn git(dir: &Path) -> Command {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(dir);
+    command
+}
+
+fn run_git(mut command: Command) -> anyhow::Result<String> {
+    let output = command.output().context("Failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to run {:?}: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Resolves `revision` to a commit of the repository at `dir`
+pub fn rev_parse(dir: &Path, revision: &str) -> anyhow::Result<String> {
+    let mut command = git(dir);
+    command.args(["rev-parse", "--verify", "--quiet"]);
+    command.arg(format!("{}^{{commit}}", revision));
+    run_git(command).with_context(|| format!("Failed to resolve {} in {}", revision, dir.display()))
+}
+
+fn is_commit_id(revision: &str) -> bool {
+    revision.len() == 40 && revision.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Checks out `revision` of the repository at `url` into `dir`, defaulting to its `HEAD`
+///
+/// An existing clone in `dir` is fetched again unless it already contains the pinned commit.
+/// The checkout is cleaned of untracked files so that every build starts from the same tree.
+pub fn fetch(url: &str, revision: Option<&str>, dir: &Path) -> anyhow::Result<GitSource> {
+    // git runs inside `dir`, where relative paths to local repositories would not resolve
+    let url = match Path::new(url).canonicalize() {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => url.to_string(),
+    };
+    let url = url.as_str();
+    if dir.join(".git").is_dir() {
+        let mut set_url = git(dir);
+        set_url.args(["remote", "set-url", "origin", url]);
+        run_git(set_url)?;
+        let pinned = revision.filter(|revision| is_commit_id(revision));
+        if pinned.is_none_or(|revision| rev_parse(dir, revision).is_err()) {
+            info!("Fetching {}", url);
+            let mut fetch = git(dir);
+            fetch.args(["fetch", "--tags", "--force", "--prune", "origin"]);
+            run_git(fetch)?;
+            let mut head = git(dir);
+            head.args(["remote", "set-head", "origin", "--auto"]);
+            run_git(head)?;
+        }
+    } else {
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+        std::fs::create_dir_all(dir)?;
+        info!("Cloning {}", url);
+        let mut clone = git(dir);
+        clone.args(["clone", "--quiet", "--no-checkout", url, "."]);
+        run_git(clone)?;
+    }
+
+    // Branches are resolved through their remote tracking refs, which follow every fetch, unlike
+    // the local branch that the clone created for the default branch
+    let revision = revision.unwrap_or("origin/HEAD");
+    let commit = rev_parse(dir, &format!("refs/remotes/origin/{}", revision))
+        .or_else(|_| rev_parse(dir, revision))
+        .with_context(|| format!("Revision {} not found in {}", revision, url))?;
+    let mut checkout = git(dir);
+    checkout.args(["checkout", "--quiet", "--force", "--detach", &commit]);
+    run_git(checkout)?;
+    let mut clean = git(dir);
+    clean.args(["clean", "--quiet", "-ffdx"]);
+    run_git(clean)?;
+    info!("Checked out {} at {}", url, commit);
+
+    Ok(GitSource {
+        url: url.to_string(),
+        commit,
+    })
+}
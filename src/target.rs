@@ -0,0 +1,164 @@
+use anyhow::bail;
+use oci_distribution::manifest::Platform;
+
+/// Target triple this tool was compiled for
+pub const HOST: &str = env!("HOST_TARGET");
+/// Target triple of wasm modules
+pub const WASM: &str = "wasm32-unknown-wasi";
+
+/// Derives the OCI platform (GOARCH/GOOS naming) of a Rust target triple
+pub fn platform(target: &str) -> anyhow::Result<Platform> {
+    let components: Vec<&str> = target.split('-').collect();
+    let (architecture, variant) = match components[0] {
+        "x86_64" => ("amd64", None),
+        "aarch64" | "arm64" => ("arm64", Some("v8")),
+        "i386" | "i586" | "i686" => ("386", None),
+        arch if arch.starts_with("armv7") || arch.starts_with("thumbv7") => ("arm", Some("v7")),
+        arch if arch.starts_with("armv5") => ("arm", Some("v5")),
+        arch if arch.starts_with("arm") => ("arm", Some("v6")),
+        "riscv64gc" | "riscv64" => ("riscv64", None),
+        "powerpc64le" => ("ppc64le", None),
+        "powerpc64" => ("ppc64", None),
+        "s390x" => ("s390x", None),
+        "mips64el" => ("mips64le", None),
+        "loongarch64" => ("loong64", None),
+        "wasm32" => ("wasm", None),
+        arch => bail!("Unsupported architecture {:?} in target {}", arch, target),
+    };
+    let has = |component: &str| components[1..].iter().any(|c| c.starts_with(component));
+    let os = if has("android") {
+        "android"
+    } else if has("linux") {
+        "linux"
+    } else if has("darwin") {
+        "darwin"
+    } else if has("ios") {
+        "ios"
+    } else if has("windows") {
+        "windows"
+    } else if has("freebsd") {
+        "freebsd"
+    } else if has("netbsd") {
+        "netbsd"
+    } else if has("openbsd") {
+        "openbsd"
+    } else if has("illumos") {
+        "illumos"
+    } else if has("wasi") {
+        "wasip1"
+    } else if architecture == "wasm" {
+        "unknown"
+    } else {
+        bail!("Unsupported operating system in target {}", target)
+    };
+
+    Ok(Platform {
+        architecture: architecture.to_string(),
+        os: os.to_string(),
+        os_version: None,
+        os_features: None,
+        variant: variant.map(str::to_string),
+        features: None,
+    })
+}
+
+pub fn is_windows(target: &str) -> bool {
+    target.contains("-windows")
+}
+
+pub fn is_msvc(target: &str) -> bool {
+    target.ends_with("-msvc")
+}
+
+pub fn is_apple(target: &str) -> bool {
+    target.contains("-apple-")
+}
+
+/// Maps a Rust target triple to the triple understood by `zig cc -target`
+pub fn zig_triple(target: &str) -> anyhow::Result<String> {
+    let components: Vec<&str> = target.split('-').collect();
+    let arch = match components[0] {
+        "i386" | "i586" | "i686" => "x86",
+        "riscv64gc" => "riscv64",
+        "arm64" => "aarch64",
+        arch if arch.starts_with("arm") => "arm",
+        arch if arch.starts_with("thumbv7") => "thumb",
+        arch => arch,
+    };
+    let env = components.last().copied().unwrap_or_default();
+    Ok(if is_apple(target) {
+        if target.contains("-ios") {
+            format!("{}-ios", arch)
+        } else {
+            format!("{}-macos", arch)
+        }
+    } else if is_windows(target) {
+        format!("{}-windows-{}", arch, env)
+    } else if target.contains("-linux-") {
+        format!("{}-linux-{}", arch, env)
+    } else if target.contains("-freebsd") {
+        format!("{}-freebsd", arch)
+    } else if target.contains("-wasi") {
+        format!("{}-wasi", arch)
+    } else {
+        bail!("No zig target known for {}", target)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platforms() {
+        let cases = [
+            ("x86_64-unknown-linux-gnu", "linux", "amd64", None),
+            ("x86_64-unknown-linux-musl", "linux", "amd64", None),
+            ("aarch64-apple-darwin", "darwin", "arm64", Some("v8")),
+            ("x86_64-pc-windows-msvc", "windows", "amd64", None),
+            ("x86_64-pc-windows-gnu", "windows", "amd64", None),
+            ("armv7-unknown-linux-gnueabihf", "linux", "arm", Some("v7")),
+            ("i686-pc-windows-msvc", "windows", "386", None),
+            ("i686-unknown-linux-gnu", "linux", "386", None),
+            ("wasm32-unknown-wasi", "wasip1", "wasm", None),
+        ];
+        for (target, os, architecture, variant) in cases {
+            let platform = platform(target).unwrap();
+            assert_eq!(
+                (
+                    platform.os.as_str(),
+                    platform.architecture.as_str(),
+                    platform.variant.as_deref()
+                ),
+                (os, architecture, variant),
+                "{}",
+                target
+            );
+        }
+        assert!(platform("sparc-unknown-linux-gnu").is_err());
+        assert!(platform("x86_64-unknown-redox").is_err());
+    }
+
+    #[test]
+    fn zig_triples() {
+        let cases = [
+            ("x86_64-unknown-linux-gnu", "x86_64-linux-gnu"),
+            ("x86_64-unknown-linux-musl", "x86_64-linux-musl"),
+            ("aarch64-apple-darwin", "aarch64-macos"),
+            ("x86_64-apple-darwin", "x86_64-macos"),
+            ("aarch64-apple-ios", "aarch64-ios"),
+            ("x86_64-pc-windows-msvc", "x86_64-windows-msvc"),
+            ("x86_64-pc-windows-gnu", "x86_64-windows-gnu"),
+            ("armv7-unknown-linux-gnueabihf", "arm-linux-gnueabihf"),
+            ("armv7-unknown-linux-musleabihf", "arm-linux-musleabihf"),
+            ("i686-pc-windows-msvc", "x86-windows-msvc"),
+            ("i686-unknown-linux-gnu", "x86-linux-gnu"),
+            ("riscv64gc-unknown-linux-gnu", "riscv64-linux-gnu"),
+            ("x86_64-unknown-freebsd", "x86_64-freebsd"),
+        ];
+        for (target, triple) in cases {
+            assert_eq!(zig_triple(target).unwrap(), triple, "{}", target);
+        }
+        assert!(zig_triple("x86_64-unknown-redox").is_err());
+    }
+}